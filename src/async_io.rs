@@ -0,0 +1,259 @@
+/* Future-based reads and writes, woken by the interrupt handler
+ *
+ * A task awaiting data or room to transmit registers its waker and enables
+ * the RX data or THR empty interrupt. handle_interrupt() wakes the task and
+ * masks that interrupt again, whether or not a task was waiting, until a task
+ * next has to wait, so an unserviced RX FIFO can't hold the IRQ line asserted
+ * in the meantime. Code that reads the RX FIFO itself after handle_interrupt()
+ * should use a BufferedUART, or re-enable the interrupts it wants.
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See README and LICENSE for usage and copying.
+ */
+
+use core::future::poll_fn;
+use core::task::{Context, Poll};
+use atomic_waker::AtomicWaker;
+use super::*;
+
+/* tasks waiting on this UART */
+#[derive(Debug, Default)]
+pub(crate) struct Wakers
+{
+    rx: AtomicWaker,
+    tx: AtomicWaker
+}
+
+impl Wakers
+{
+    /* wake any task waiting to transmit */
+    pub(crate) fn wake_tx(&self)
+    {
+        self.tx.wake()
+    }
+}
+
+impl<R: Registers> UART<R>
+{
+    /* wait for at least one byte to arrive, then read as many as are available into buf
+       without waiting further, returning the number read. as with read_byte(), a byte with
+       a line error is reported as a fault, and ends a read early if it isn't the first */
+    pub async fn read_async(&self, buf: &mut [u8]) -> Result<usize, Fault>
+    {
+        if buf.is_empty() { return Ok(0) }
+
+        loop
+        {
+            poll_fn(|cx| self.poll_rx_ready(cx)).await;
+            if let Some(result) = self.read_available(buf) { return result }
+        }
+    }
+
+    /* wait for the TX FIFO to empty, and for CTS if we're watching it,
+       then fill the FIFO from buf, returning the number of bytes written */
+    pub async fn write_async(&self, buf: &[u8]) -> Result<usize, Fault>
+    {
+        if buf.is_empty() { return Ok(0) }
+
+        poll_fn(|cx| self.poll_tx_ready(cx)).await;
+        poll_fn(|cx| self.poll_clear_to_send(cx)).await;
+
+        Ok(self.fill_tx_fifo(buf))
+    }
+
+    /* wait for the last byte to leave the transmitter */
+    pub async fn flush_async(&self) -> Result<(), Fault>
+    {
+        poll_fn(|cx| self.poll_tx_ready(cx)).await;
+
+        /* there's no interrupt for the shift register emptying, but it's at most a character away */
+        match self.tx_timeout.wait_until(|| self.is_transmitter_idle())
+        {
+            true => Ok(()),
+            false => Err(Fault::TxNotEmpty)
+        }
+    }
+
+    fn poll_rx_ready(&self, cx: &mut Context<'_>) -> Poll<()>
+    {
+        let ready = || self.overrun_pending.load(Ordering::Relaxed) || self.is_data_ready();
+        if ready() { return Poll::Ready(()) }
+
+        /* check again after arming the interrupt in case a byte arrived in between */
+        self.wakers.rx.register(cx.waker());
+        self.unmask_interrupt(IRQ_EN_RX_DATA | IRQ_EN_LINE_STATUS);
+        if ready() { Poll::Ready(()) } else { Poll::Pending }
+    }
+
+    fn poll_tx_ready(&self, cx: &mut Context<'_>) -> Poll<()>
+    {
+        if self.is_transmit_empty() { return Poll::Ready(()) }
+
+        self.wakers.tx.register(cx.waker());
+        self.unmask_interrupt(IRQ_EN_TX_EMPTY);
+        if self.is_transmit_empty() { Poll::Ready(()) } else { Poll::Pending }
+    }
+
+    fn poll_clear_to_send(&self, cx: &mut Context<'_>) -> Poll<()>
+    {
+        if self.clear_to_send() { return Poll::Ready(()) }
+
+        /* with XON/XOFF, whatever reads the XON wakes us */
+        self.wakers.tx.register(cx.waker());
+        if self.flow_control == FlowControl::RtsCts { self.unmask_interrupt(IRQ_EN_MODEM_STATUS) }
+        if self.clear_to_send() { Poll::Ready(()) } else { Poll::Pending }
+    }
+
+    /* called by handle_interrupt() to wake any tasks waiting on the events it found */
+    pub(crate) fn wake_tasks(&self, events: &Events)
+    {
+        /* only reading the RX FIFO quietens these, which is up to the task */
+        if events.rx_ready() || events.line_status
+        {
+            self.mask_interrupt(IRQ_EN_RX_DATA | IRQ_EN_LINE_STATUS);
+            self.wakers.rx.wake();
+        }
+
+        if events.tx_empty { self.mask_interrupt(IRQ_EN_TX_EMPTY) }
+
+        if events.tx_empty || events.modem_status.is_some()
+        {
+            if let Some(waker) = self.wakers.tx.take()
+            {
+                if self.flow_control == FlowControl::RtsCts { self.mask_interrupt(IRQ_EN_MODEM_STATUS) }
+                waker.wake();
+            }
+        }
+    }
+
+    fn unmask_interrupt(&self, bits: u8)
+    {
+        self.irq_enable.fetch_or(bits, Ordering::Relaxed);
+        self.write_irq_enable();
+    }
+
+    fn mask_interrupt(&self, bits: u8)
+    {
+        self.irq_enable.fetch_and(!bits, Ordering::Relaxed);
+        self.write_irq_enable();
+    }
+}
+
+impl<R: Registers> embedded_io_async::Read for UART<R>
+{
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Fault>
+    {
+        self.read_async(buf).await
+    }
+}
+
+impl<R: Registers> embedded_io_async::Write for UART<R>
+{
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Fault>
+    {
+        self.write_async(buf).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Fault>
+    {
+        self.flush_async().await
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use core::future::Future;
+    use core::pin::pin;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+    use std::task::{Wake, Waker};
+    use model::Model;
+
+    /* count how many times a task is woken */
+    struct WakeCounter(AtomicUsize);
+
+    impl Wake for WakeCounter
+    {
+        fn wake(self: Arc<Self>) { self.0.fetch_add(1, Ordering::SeqCst); }
+    }
+
+    fn poll<F: Future>(future: core::pin::Pin<&mut F>, waker: &Waker) -> Poll<F::Output>
+    {
+        future.poll(&mut Context::from_waker(waker))
+    }
+
+    #[test]
+    fn read_woken_by_interrupt()
+    {
+        let model = Model::new();
+        let uart = UART::with_registers(&model, Config::default().with_interrupts(InterruptEnable::default())).unwrap();
+        let counter = Arc::new(WakeCounter(AtomicUsize::new(0)));
+        let waker = Waker::from(counter.clone());
+
+        let mut buf = [0u8; 4];
+        {
+            let mut read = pin!(uart.read_async(&mut buf));
+            assert_eq!(poll(read.as_mut(), &waker), Poll::Pending);
+            assert!(model.interrupt_enable() & IRQ_EN_RX_DATA != 0);
+
+            model.receive(b'o');
+            model.receive(b'k');
+            model.character_timeout();
+            assert!(model.interrupt_pending());
+            assert!(uart.handle_interrupt().rx_timeout);
+            assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+
+            /* the RX interrupt stays masked until the task waits again */
+            assert!(!model.interrupt_pending());
+            assert_eq!(poll(read.as_mut(), &waker), Poll::Ready(Ok(2)));
+        }
+        assert_eq!(&buf[..2], b"ok");
+    }
+
+    #[test]
+    fn unawaited_rx_masked()
+    {
+        let model = Model::new();
+        let uart = UART::with_registers(&model, Config::default()).unwrap();
+
+        /* nobody's waiting for this byte, so it mustn't keep the IRQ line asserted */
+        model.receive(b'a');
+        model.character_timeout();
+        assert!(uart.handle_interrupt().rx_timeout);
+        assert!(!model.interrupt_pending());
+        assert!(!uart.interrupt_enable().rx_data);
+
+        /* until a task waits for it */
+        let waker = Waker::from(Arc::new(WakeCounter(AtomicUsize::new(0))));
+        let mut buf = [0u8; 1];
+        assert_eq!(poll(pin!(uart.read_async(&mut buf)).as_mut(), &waker), Poll::Ready(Ok(1)));
+        assert_eq!(buf[0], b'a');
+    }
+
+    #[test]
+    fn write_woken_by_interrupt()
+    {
+        let model = Model::new();
+        let uart = UART::with_registers(&model, Config::default()).unwrap();
+        let counter = Arc::new(WakeCounter(AtomicUsize::new(0)));
+        let waker = Waker::from(counter.clone());
+
+        /* the first burst goes straight into the FIFO */
+        model.set_tx_stalled(true);
+        let message = [b'.'; 20];
+        assert_eq!(poll(pin!(uart.write_async(&message)).as_mut(), &waker), Poll::Ready(Ok(16)));
+
+        let mut write = pin!(uart.write_async(&message[16..]));
+        assert_eq!(poll(write.as_mut(), &waker), Poll::Pending);
+
+        model.set_tx_stalled(false);
+        assert!(uart.handle_interrupt().tx_empty);
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+        assert_eq!(poll(write.as_mut(), &waker), Poll::Ready(Ok(4)));
+        assert_eq!(poll(pin!(uart.flush_async()).as_mut(), &waker), Poll::Ready(Ok(())));
+        assert_eq!(core::iter::from_fn(|| model.take_tx()).count(), 20);
+    }
+}
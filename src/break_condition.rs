@@ -0,0 +1,133 @@
+/* send and detect break conditions, where the line is held low for longer than a character
+ *
+ * A break is often used out-of-band, eg to drop a board into its debug monitor.
+ * A received break is reported by read_byte() as Fault::Break, and by
+ * handle_interrupt() as Events::break_received, rather than as a 0x00 byte.
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See README and LICENSE for usage and copying.
+ */
+
+use super::*;
+
+/* characters' worth of zeros clocked out behind a break to time its minimum length */
+const BREAK_MIN_CHARACTERS: usize = 2;
+
+impl<R: Registers> UART<R>
+{
+    /* start or stop holding the TX line low. anything still being transmitted is cut short */
+    pub fn set_break(&self, enabled: bool)
+    {
+        let lcr = self.read_reg(REG_LINE_CONTROL);
+        self.write_reg(REG_LINE_CONTROL, if enabled { lcr | LINE_CONTROL_BREAK } else { lcr & !LINE_CONTROL_BREAK });
+    }
+
+    /* wait, according to this UART's TX timeout, for everything queued to be transmitted,
+       then hold the TX line low for at least ticks of clock. the transmitter clocks out two
+       characters of zeros behind the break first, so however short ticks is, the break lasts
+       at least two character times at the current baud rate, long enough for the other end
+       to see it. fails with TxNotEmpty if the transmitter stalls, with the break ended */
+    pub fn send_break(&self, clock: &dyn TimeSource, ticks: u64) -> Result<(), Fault>
+    {
+        if !self.tx_timeout.wait_until(|| self.is_transmitter_idle()) { return Err(Fault::TxNotEmpty) }
+
+        let start = clock.now();
+        self.set_break(true);
+
+        let timed = (0..BREAK_MIN_CHARACTERS).all(|_|
+        {
+            let ready = self.tx_timeout.wait_until(|| self.is_transmit_empty());
+            if ready { self.write_reg(REG_DATA, 0) }
+            ready
+        }) && self.tx_timeout.wait_until(|| self.is_transmitter_idle());
+
+        while timed && clock.now().wrapping_sub(start) < ticks {}
+
+        self.set_break(false);
+        if timed { Ok(()) } else { Err(Fault::TxNotEmpty) }
+    }
+
+    /* return true if a break is waiting at the front of the RX FIFO */
+    pub(crate) fn break_pending(&self) -> bool
+    {
+        self.rx_errors_pending.load(Ordering::Relaxed) & LINE_STATUS_BI != 0
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use core::sync::atomic::AtomicU64;
+    use model::{Model, RxError};
+
+    /* a clock that ticks every time it's read */
+    struct Ticker(AtomicU64);
+
+    impl TimeSource for Ticker
+    {
+        fn now(&self) -> u64 { self.0.fetch_add(1, Ordering::Relaxed) }
+    }
+
+    static TICKER: Ticker = Ticker(AtomicU64::new(0));
+
+    #[test]
+    fn send()
+    {
+        let model = Model::new();
+        let uart = UART::with_registers(&model, Config::default()).unwrap();
+
+        uart.set_break(true);
+        assert_eq!(model.line_control(), LINE_CONTROL_BREAK | 0b11);
+        uart.set_break(false);
+        assert_eq!(model.line_control(), 0b11);
+
+        uart.send_byte(b'!').unwrap();
+        assert_eq!(uart.send_break(&TICKER, 10), Ok(()));
+        assert!(TICKER.0.load(Ordering::Relaxed) >= 10);
+        assert_eq!(model.line_control(), 0b11);
+
+        /* the zeros timing the break don't make it onto the line */
+        assert_eq!(model.take_tx(), Some(b'!'));
+        assert_eq!(model.take_tx(), None);
+
+        model.set_tx_stalled(true);
+        uart.send_byte(b'!').unwrap();
+        assert_eq!(uart.send_break(&TICKER, 10), Err(Fault::TxNotEmpty));
+        model.set_tx_stalled(false);
+        assert_eq!(uart.send_break(&TICKER, 0), Ok(()));
+        assert_eq!(model.take_tx(), Some(b'!'));
+        assert_eq!(model.take_tx(), None);
+    }
+
+    #[test]
+    fn receive()
+    {
+        let model = Model::new();
+        let interrupts = InterruptEnable { rx_data: true, line_status: true, ..InterruptEnable::default() };
+        let uart = UART::with_registers(&model, Config::default().with_interrupts(interrupts)).unwrap();
+
+        model.receive(b'a');
+        model.receive_with_error(0, RxError::Break);
+        model.receive(b'b');
+        model.character_timeout();
+
+        assert!(!uart.handle_interrupt().break_received);
+        assert_eq!(uart.read_byte(), Ok(b'a'));
+
+        /* with the async feature, handle_interrupt() leaves RX interrupts masked until they're wanted again */
+        uart.set_interrupt_enable(interrupts);
+
+        /* the break reaches the front of the FIFO */
+        let events = uart.handle_interrupt();
+        assert!(events.line_status && events.break_received);
+
+        /* the break waiting to be read doesn't make later interrupts look like ours */
+        let events = uart.handle_interrupt();
+        assert!(events.break_received && !events.any());
+        assert_eq!(uart.read_byte(), Err(Fault::Break));
+        assert_eq!(uart.read_byte(), Ok(b'b'));
+        assert_eq!(uart.line_error_counts().breaks, 1);
+    }
+}
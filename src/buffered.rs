@@ -0,0 +1,515 @@
+/* interrupt-driven, buffered UART I/O
+ *
+ * The IRQ handler fills an RX ring from the chip's RX FIFO and drains a TX ring
+ * into the chip's TX FIFO in bursts, while thread context reads and writes the
+ * rings without blocking. The rings are single-producer single-consumer queues
+ * built from atomics in caller-provided storage, so no allocator or locks are needed:
+ *
+ *   static RX_SLOTS: [AtomicU8; 256] = RingBuffer::storage();
+ *   static RX: RingBuffer = RingBuffer::new(&RX_SLOTS);
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See README and LICENSE for usage and copying.
+ */
+
+use core::sync::atomic::{AtomicU8, AtomicU32, AtomicUsize, AtomicBool, Ordering};
+use super::*;
+
+/* no XON or XOFF waiting to be sent */
+const NO_FLOW_CONTROL_CHAR: u8 = 0;
+
+/* a fixed-size queue of bytes shared between one producer and one consumer,
+   eg an interrupt handler and a thread */
+#[derive(Debug)]
+pub struct RingBuffer<'a>
+{
+    slots: &'a [AtomicU8],
+    head: AtomicUsize,  /* total bytes ever pushed, only written by the producer */
+    tail: AtomicUsize   /* total bytes ever popped, only written by the consumer */
+}
+
+impl<'a> RingBuffer<'a>
+{
+    /* create an empty queue that holds up to slots.len() bytes */
+    pub const fn new(slots: &'a [AtomicU8]) -> Self
+    {
+        RingBuffer { slots, head: AtomicUsize::new(0), tail: AtomicUsize::new(0) }
+    }
+
+    /* return N bytes of zeroed storage for a queue, suitable for a static */
+    pub const fn storage<const N: usize>() -> [AtomicU8; N]
+    {
+        /* each element of the array gets its own copy of this */
+        #[allow(clippy::declare_interior_mutable_const)]
+        const EMPTY: AtomicU8 = AtomicU8::new(0);
+        [EMPTY; N]
+    }
+
+    /* add a byte to the queue, or return false if it's full. producer only */
+    pub fn push(&self, byte: u8) -> bool
+    {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head.wrapping_sub(tail) >= self.slots.len() { return false }
+
+        self.slots[head % self.slots.len()].store(byte, Ordering::Relaxed);
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /* remove the oldest byte from the queue, if any. consumer only */
+    pub fn pop(&self) -> Option<u8>
+    {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        if head == tail { return None }
+
+        let byte = self.slots[tail % self.slots.len()].load(Ordering::Relaxed);
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Some(byte)
+    }
+
+    /* return the oldest byte in the queue without removing it. consumer only */
+    pub fn peek(&self) -> Option<u8>
+    {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if self.head.load(Ordering::Acquire) == tail { return None }
+        Some(self.slots[tail % self.slots.len()].load(Ordering::Relaxed))
+    }
+
+    /* add as much of data as there's room for, and return how many bytes were added. producer only */
+    pub fn push_slice(&self, data: &[u8]) -> usize
+    {
+        data.iter().take_while(|byte| self.push(**byte)).count()
+    }
+
+    /* remove up to buf.len() of the oldest bytes into buf, and return how many were removed. consumer only */
+    pub fn pop_into(&self, buf: &mut [u8]) -> usize
+    {
+        let mut popped = 0;
+        for slot in buf.iter_mut()
+        {
+            match self.pop()
+            {
+                Some(byte) => *slot = byte,
+                None => break
+            }
+            popped += 1;
+        }
+        popped
+    }
+
+    /* number of bytes in the queue */
+    pub fn len(&self) -> usize
+    {
+        let tail = self.tail.load(Ordering::Acquire);
+        self.head.load(Ordering::Acquire).wrapping_sub(tail)
+    }
+
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+    pub fn is_full(&self) -> bool { self.len() >= self.capacity() }
+    pub fn capacity(&self) -> usize { self.slots.len() }
+}
+
+/* a UART with interrupt-fed RX and TX rings. call handle_interrupt() from the
+   UART's IRQ handler, and read() and write() from one thread */
+#[derive(Debug)]
+pub struct BufferedUART<'a, R: Registers = Mmio>
+{
+    uart: UART<R>,
+    rx: &'a RingBuffer<'a>,
+    tx: &'a RingBuffer<'a>,
+    tx_busy: AtomicBool,        /* held by whichever context is feeding the TX FIFO */
+    rx_dropped: AtomicU32,      /* received bytes lost because the RX ring was full */
+    rx_throttled: AtomicBool,   /* the other end has been asked to pause because the RX ring is nearly full */
+    rx_flow_char: AtomicU8      /* XON or XOFF to send ahead of the TX ring, or NO_FLOW_CONTROL_CHAR */
+}
+
+impl<'a, R: Registers> BufferedUART<'a, R>
+{
+    /* take over an initialized UART, enabling the interrupts needed to drive the rings.
+       with RTS/CTS flow control, that includes modem status interrupts to restart
+       transmission when CTS is asserted */
+    pub fn new(uart: UART<R>, rx: &'a RingBuffer<'a>, tx: &'a RingBuffer<'a>) -> Self
+    {
+        let mut interrupts = uart.interrupt_enable();
+        interrupts.rx_data = true;
+        interrupts.tx_empty = true;
+        interrupts.line_status = true;
+        if uart.flow_control() == FlowControl::RtsCts { interrupts.modem_status = true }
+        uart.set_interrupt_enable(interrupts);
+
+        BufferedUART
+        {
+            uart,
+            rx,
+            tx,
+            tx_busy: AtomicBool::new(false),
+            rx_dropped: AtomicU32::new(0),
+            rx_throttled: AtomicBool::new(false),
+            rx_flow_char: AtomicU8::new(NO_FLOW_CONTROL_CHAR)
+        }
+    }
+
+    /* access the underlying UART, eg for its error counts */
+    pub fn uart(&self) -> &UART<R>
+    {
+        &self.uart
+    }
+
+    /* queue as much of data as there's room for, and return how many bytes were queued */
+    pub fn write(&self, data: &[u8]) -> usize
+    {
+        let queued = self.tx.push_slice(data);
+        self.pump_tx();
+        queued
+    }
+
+    /* fetch up to buf.len() received bytes into buf and return how many were fetched */
+    pub fn read(&self, buf: &mut [u8]) -> usize
+    {
+        let fetched = self.rx.pop_into(buf);
+
+        /* let the other end resume once there's plenty of room again */
+        if self.rx.len() <= self.rx.capacity() / 4
+        {
+            self.throttle_rx(false);
+            if self.rx_flow_char.load(Ordering::Relaxed) != NO_FLOW_CONTROL_CHAR { self.pump_tx() }
+        }
+
+        fetched
+    }
+
+    /* return the number of received bytes lost because the RX ring was full */
+    pub fn rx_dropped(&self) -> u32
+    {
+        self.rx_dropped.load(Ordering::Relaxed)
+    }
+
+    /* call this from the UART's IRQ handler to move data between the chip and the rings.
+       returns the interrupt causes serviced. Events::any() is false if the interrupt wasn't ours */
+    pub fn handle_interrupt(&self) -> Events
+    {
+        let mut serviced = Events::default();
+
+        for _ in 0..IRQ_PASSES_MAX
+        {
+            let events = self.uart.service_interrupt();
+            if !events.any() { break }
+
+            if events.rx_ready() { self.drain_rx() }
+
+            /* transmission may have been held up by flow control until now */
+            if events.tx_empty || events.modem_status.is_some() || events.rx_ready() { self.pump_tx() }
+
+            serviced.line_status |= events.line_status;
+            serviced.break_received |= events.break_received;
+            serviced.rx_data |= events.rx_data;
+            serviced.rx_timeout |= events.rx_timeout;
+            serviced.tx_empty |= events.tx_empty;
+            serviced.modem_status = events.modem_status.or(serviced.modem_status);
+        }
+
+        serviced
+    }
+
+    /* move everything in the RX FIFO into the RX ring */
+    fn drain_rx(&self)
+    {
+        loop
+        {
+            let byte = match self.uart.read_byte_timeout(Timeout::NonBlocking)
+            {
+                Ok(byte) => byte,
+
+                /* pass on damaged bytes: the errors are counted by the UART */
+                Err(Fault::Parity(byte)) | Err(Fault::Framing(byte)) => byte,
+                Err(Fault::Overrun) | Err(Fault::Break) => continue,
+                Err(_) => return
+            };
+
+            if !self.rx.push(byte) { self.rx_dropped.fetch_add(1, Ordering::Relaxed); }
+
+            /* ask the other end to pause while the ring is nearly full */
+            if self.rx.len() >= self.rx.capacity() - self.rx.capacity() / 4 { self.throttle_rx(true) }
+        }
+    }
+
+    /* ask the other end to pause, or to resume, sending to us. RTS changes straight away.
+       XON and XOFF are left for pump_tx() to send, as only one context at a time can feed
+       the TX FIFO, and the other end only counts as asked once one has gone out */
+    fn throttle_rx(&self, throttle: bool)
+    {
+        match self.uart.flow_control()
+        {
+            FlowControl::XonXoff =>
+            {
+                let byte = match (throttle, self.rx_throttled.load(Ordering::Relaxed))
+                {
+                    (true, false) => XOFF,
+                    (false, true) => XON,
+                    _ => NO_FLOW_CONTROL_CHAR
+                };
+                self.rx_flow_char.store(byte, Ordering::Relaxed);
+            },
+            _ =>
+            {
+                if self.rx_throttled.swap(throttle, Ordering::Relaxed) != throttle { self.uart.throttle_rx(throttle) }
+            }
+        }
+    }
+
+    /* send a waiting XON or XOFF if there's room. it goes out even while we're paused.
+       only call this while holding tx_busy */
+    fn send_flow_control_char(&self)
+    {
+        let byte = self.rx_flow_char.load(Ordering::Relaxed);
+        if byte == NO_FLOW_CONTROL_CHAR || !self.uart.is_transmit_empty() { return }
+
+        self.uart.write_reg(REG_DATA, byte);
+        self.rx_throttled.store(byte == XOFF, Ordering::Relaxed);
+
+        /* unless the other context has changed its mind in the meantime */
+        self.rx_flow_char.compare_exchange(byte, NO_FLOW_CONTROL_CHAR, Ordering::Relaxed, Ordering::Relaxed).ok();
+    }
+
+    /* return true if there's an XON or XOFF, or queued data, that can be sent now */
+    fn tx_ready(&self) -> bool
+    {
+        match self.rx_flow_char.load(Ordering::Relaxed)
+        {
+            NO_FLOW_CONTROL_CHAR => !self.tx.is_empty() && self.uart.ready_to_send(),
+            _ => self.uart.is_transmit_empty()
+        }
+    }
+
+    /* move bytes from the TX ring into the TX FIFO in bursts while there's room.
+       this is called from both thread and interrupt context, so only one caller at
+       a time feeds the FIFO. a caller that finds it busy leaves the work to the holder,
+       which checks the ring again after letting go */
+    fn pump_tx(&self)
+    {
+        for _ in 0..IRQ_PASSES_MAX
+        {
+            if self.tx_busy.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_err()
+            {
+                return;
+            }
+
+            self.send_flow_control_char();
+
+            /* when THRE is set, the whole TX FIFO is empty. CTS is checked before each burst */
+            while !self.tx.is_empty() && self.uart.ready_to_send()
+            {
+                for _ in 0..self.uart.tx_fifo_depth()
+                {
+                    match self.tx.pop()
+                    {
+                        Some(byte) => self.uart.write_reg(REG_DATA, byte),
+                        None => break
+                    }
+                }
+            }
+
+            self.tx_busy.store(false, Ordering::Release);
+
+            /* bytes queued while we held the FIFO, and room to send them? */
+            if !self.tx_ready() { return }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use model::Model;
+
+    #[test]
+    fn ring_buffer_wraps()
+    {
+        let slots: [AtomicU8; 4] = RingBuffer::storage();
+        let ring = RingBuffer::new(&slots);
+
+        for round in 0..3
+        {
+            for byte in 0..4 { assert!(ring.push(round * 4 + byte)) }
+            assert!(ring.is_full());
+            assert!(!ring.push(0xff));
+            for byte in 0..4 { assert_eq!(ring.pop(), Some(round * 4 + byte)) }
+            assert_eq!(ring.pop(), None);
+        }
+
+        let mut buf = [0u8; 4];
+        assert_eq!(ring.push_slice(b"abcdef"), 4);
+        assert_eq!(ring.peek(), Some(b'a'));
+        assert_eq!(ring.pop_into(&mut buf[..3]), 3);
+        assert_eq!(ring.pop_into(&mut buf), 1);
+        assert_eq!(buf[0], b'd');
+    }
+
+    #[test]
+    fn interrupt_fed_rx()
+    {
+        let model = Model::new();
+        let (rx_slots, tx_slots): ([AtomicU8; 8], [AtomicU8; 8]) = (RingBuffer::storage(), RingBuffer::storage());
+        let (rx, tx) = (RingBuffer::new(&rx_slots), RingBuffer::new(&tx_slots));
+        let uart = BufferedUART::new(UART::with_registers(&model, Config::default()).unwrap(), &rx, &tx);
+
+        let mut buf = [0u8; 16];
+        assert_eq!(uart.read(&mut buf), 0);
+
+        /* the RX data interrupt fires at the trigger level of 14 bytes, which overflows the ring */
+        for byte in 0..14 { model.receive(byte) }
+        assert!(uart.handle_interrupt().rx_data);
+        assert_eq!(uart.rx_dropped(), 6);
+        assert_eq!(uart.read(&mut buf), 8);
+        assert_eq!(&buf[..8], &[0, 1, 2, 3, 4, 5, 6, 7]);
+
+        model.receive(b'x');
+        model.character_timeout();
+        assert!(uart.handle_interrupt().rx_timeout);
+        assert_eq!(uart.read(&mut buf), 1);
+        assert_eq!(buf[0], b'x');
+    }
+
+    #[test]
+    fn interrupt_drained_tx()
+    {
+        let model = Model::new();
+        let (rx_slots, tx_slots): ([AtomicU8; 8], [AtomicU8; 64]) = (RingBuffer::storage(), RingBuffer::storage());
+        let (rx, tx) = (RingBuffer::new(&rx_slots), RingBuffer::new(&tx_slots));
+        let uart = BufferedUART::new(UART::with_registers(&model, Config::default()).unwrap(), &rx, &tx);
+        uart.handle_interrupt();
+
+        /* the first burst fills the TX FIFO, the rest waits in the ring */
+        model.set_tx_stalled(true);
+        let message = [b'm'; 40];
+        assert_eq!(uart.write(&message), 40);
+        assert_eq!(tx.len(), 24);
+
+        /* each THR empty interrupt sends another burst */
+        model.set_tx_stalled(false);
+        model.set_tx_stalled(true);
+        assert!(uart.handle_interrupt().tx_empty);
+        assert_eq!(tx.len(), 8);
+        model.set_tx_stalled(false);
+        assert!(uart.handle_interrupt().tx_empty);
+        assert!(tx.is_empty());
+
+        let mut sent = 0;
+        while let Some(byte) = model.take_tx()
+        {
+            assert_eq!(byte, b'm');
+            sent += 1;
+        }
+        assert_eq!(sent, 40);
+    }
+
+    #[test]
+    fn rts_follows_rx_ring()
+    {
+        let model = Model::new();
+        let (rx_slots, tx_slots): ([AtomicU8; 8], [AtomicU8; 8]) = (RingBuffer::storage(), RingBuffer::storage());
+        let (rx, tx) = (RingBuffer::new(&rx_slots), RingBuffer::new(&tx_slots));
+        let config = Config::default().with_flow_control(FlowControl::RtsCts);
+        let uart = BufferedUART::new(UART::with_registers(&model, config).unwrap(), &rx, &tx);
+
+        /* RTS drops once the ring is three quarters full */
+        for byte in 0..5 { model.receive(byte) }
+        model.character_timeout();
+        uart.handle_interrupt();
+        assert!(model.modem_control().rts);
+        model.receive(5);
+        model.character_timeout();
+        uart.handle_interrupt();
+        assert!(!model.modem_control().rts);
+
+        /* and comes back once it's drained to a quarter */
+        let mut buf = [0u8; 3];
+        assert_eq!(uart.read(&mut buf), 3);
+        assert!(!model.modem_control().rts);
+        assert_eq!(uart.read(&mut buf[..1]), 1);
+        assert!(model.modem_control().rts);
+
+        /* transmission waits for CTS, and resumes from the modem status interrupt */
+        assert_eq!(uart.write(b"go"), 2);
+        assert_eq!(model.take_tx(), None);
+        model.set_modem_inputs(true, false, false, false);
+        assert!(uart.handle_interrupt().modem_status.unwrap().cts);
+        assert_eq!(model.take_tx(), Some(b'g'));
+        assert_eq!(model.take_tx(), Some(b'o'));
+    }
+
+    #[test]
+    fn xon_xoff_follows_rx_ring()
+    {
+        let model = Model::new();
+        let (rx_slots, tx_slots): ([AtomicU8; 4], [AtomicU8; 8]) = (RingBuffer::storage(), RingBuffer::storage());
+        let (rx, tx) = (RingBuffer::new(&rx_slots), RingBuffer::new(&tx_slots));
+        let config = Config::default().with_flow_control(FlowControl::XonXoff);
+        let uart = BufferedUART::new(UART::with_registers(&model, config).unwrap(), &rx, &tx);
+
+        /* XOFF from the other end holds our output, and isn't passed on as data */
+        model.receive(0x13);
+        model.character_timeout();
+        uart.handle_interrupt();
+        assert_eq!(uart.write(b"hi"), 2);
+        assert_eq!(model.take_tx(), None);
+
+        /* our XOFF goes out once the ring is three quarters full, and XON once it's drained */
+        for byte in b"abc" { model.receive(*byte) }
+        model.character_timeout();
+        uart.handle_interrupt();
+        assert_eq!(model.take_tx(), Some(0x13));
+        let mut buf = [0u8; 4];
+        assert_eq!(uart.read(&mut buf), 3);
+        assert_eq!(&buf[..3], b"abc");
+        assert_eq!(model.take_tx(), Some(0x11));
+
+        /* XON from the other end lets our output go */
+        model.receive(0x11);
+        model.character_timeout();
+        uart.handle_interrupt();
+        assert_eq!(model.take_tx(), Some(b'h'));
+        assert_eq!(model.take_tx(), Some(b'i'));
+        assert_eq!(uart.read(&mut buf), 0);
+    }
+
+    #[test]
+    fn xoff_waits_for_room()
+    {
+        let model = Model::new();
+        let (rx_slots, tx_slots): ([AtomicU8; 4], [AtomicU8; 32]) = (RingBuffer::storage(), RingBuffer::storage());
+        let (rx, tx) = (RingBuffer::new(&rx_slots), RingBuffer::new(&tx_slots));
+        let config = Config::default().with_flow_control(FlowControl::XonXoff);
+        let uart = BufferedUART::new(UART::with_registers(&model, config).unwrap(), &rx, &tx);
+        uart.handle_interrupt();
+
+        /* the TX FIFO is full when the ring fills, so our XOFF has to wait its turn */
+        model.set_tx_stalled(true);
+        assert_eq!(uart.write(&[b'm'; 20]), 20);
+        for byte in b"abc" { model.receive(*byte) }
+        model.character_timeout();
+        assert!(uart.handle_interrupt().rx_timeout);
+
+        /* and goes out ahead of the rest of the ring once there's room */
+        model.set_tx_stalled(false);
+        assert!(uart.handle_interrupt().tx_empty);
+        let sent: Vec<u8> = core::iter::from_fn(|| model.take_tx()).collect();
+        assert_eq!(sent.len(), 21);
+        assert_eq!((sent[16], sent.iter().filter(|byte| **byte == b'm').count()), (0x13, 20));
+
+        /* the XON likewise */
+        model.set_tx_stalled(true);
+        assert_eq!(uart.write(&[b'n'; 16]), 16);
+        let mut buf = [0u8; 4];
+        assert_eq!(uart.read(&mut buf), 3);
+        assert_eq!(model.take_tx(), None);
+        model.set_tx_stalled(false);
+        assert!(uart.handle_interrupt().tx_empty);
+        let sent: Vec<u8> = core::iter::from_fn(|| model.take_tx()).collect();
+        assert_eq!((sent.len(), sent[16]), (17, 0x11));
+    }
+}
@@ -0,0 +1,506 @@
+/* describe how a 16550 UART should be programmed
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See README and LICENSE for usage and copying.
+ */
+
+use super::{Fault, Timeout, InterruptEnable, Variant, LOOP_MAX};
+use super::divisor::{Divisor, DEFAULT_BAUD_TOLERANCE_BP};
+
+/* the classic PC serial clock: 115200 bps with a divisor of 1 */
+pub const DEFAULT_CLOCK_HZ: u32 = 1_843_200;
+pub const DEFAULT_BAUD_RATE: u32 = 38400;
+
+/* widest register spacing accepted: registers 16 bytes apart */
+pub const MAX_REG_SHIFT: u8 = 4;
+
+/* line control register bits */
+const LINE_CONTROL_STOP_BITS: u8 = 1 << 2;  /* 1.5 or 2 stop bits rather than 1 */
+const LINE_CONTROL_PARITY_EN: u8 = 1 << 3;  /* generate and check a parity bit */
+const LINE_CONTROL_EVEN: u8 = 1 << 4;       /* even rather than odd parity */
+const LINE_CONTROL_STICK: u8 = 1 << 5;      /* force the parity bit to a fixed value */
+
+/* FIFO control register bits */
+pub(crate) const FIFO_CONTROL_ENABLE: u8 = 1 << 0;   /* enable the RX and TX FIFOs */
+pub(crate) const FIFO_CONTROL_RX_RESET: u8 = 1 << 1; /* empty the RX FIFO */
+pub(crate) const FIFO_CONTROL_TX_RESET: u8 = 1 << 2; /* empty the TX FIFO */
+pub(crate) const FIFO_CONTROL_64BYTE: u8 = 1 << 5;   /* 16750 only: use 64-byte FIFOs, set while DLAB is set */
+
+/* modem control register bits */
+const MODEM_CONTROL_DTR: u8 = 1 << 0;       /* data terminal ready */
+pub(crate) const MODEM_CONTROL_RTS: u8 = 1 << 1; /* request to send */
+const MODEM_CONTROL_OUT1: u8 = 1 << 2;      /* user-defined output 1 */
+const MODEM_CONTROL_OUT2: u8 = 1 << 3;      /* user-defined output 2, gates the IRQ line on PCs */
+const MODEM_CONTROL_LOOP: u8 = 1 << 4;      /* internal loopback */
+pub(crate) const MODEM_CONTROL_AFE: u8 = 1 << 5; /* 16750 only: automatic flow control */
+
+/* number of data bits per character */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DataBits
+{
+    Five,
+    Six,
+    Seven,
+    Eight
+}
+
+/* parity bit generated and checked per character */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Parity
+{
+    None,
+    Odd,
+    Even,
+    Mark,   /* parity bit always 1 */
+    Space   /* parity bit always 0 */
+}
+
+/* number of stop bits per character. the 16550 only offers 1.5 stop bits
+   with 5 data bits, and only offers 2 stop bits with 6 or more data bits */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopBits
+{
+    One,
+    OnePointFive,
+    Two
+}
+
+/* number of bytes in the RX FIFO that raise the RX data interrupt */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FifoTrigger
+{
+    Bytes1,
+    Bytes4,
+    Bytes8,
+    Bytes14
+}
+
+/* how transmission is paused when one end can't keep up */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FlowControl
+{
+    None,
+    RtsCts,     /* the driver holds transmission while CTS is deasserted, and a BufferedUART
+                   deasserts RTS while its RX ring is nearly full */
+    AutoRtsCts, /* the chip drives RTS from its RX FIFO level and holds transmission while CTS is
+                   deasserted, on parts that can (16650 and later). other parts fall back to RtsCts */
+    XonXoff,    /* in-band flow control for three-wire links: the driver strips XON and XOFF from
+                   received data and holds transmission between them, and a BufferedUART sends
+                   XOFF while its RX ring is nearly full and XON once it has room again */
+    AutoXonXoff /* the chip sends and acts on XON and XOFF itself, on parts with an EFR (16650,
+                   16850 and 16950). other parts fall back to XonXoff */
+}
+
+/* width of each load and store to a register. the 16550's registers
+   are 8 bits wide, so wider accesses use only the lowest 8 bits */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessWidth
+{
+    Bits8,
+    Bits16,
+    Bits32
+}
+
+impl AccessWidth
+{
+    pub fn bytes(&self) -> usize
+    {
+        match self
+        {
+            AccessWidth::Bits8 => 1,
+            AccessWidth::Bits16 => 2,
+            AccessWidth::Bits32 => 4
+        }
+    }
+}
+
+/* output lines driven by the modem control register */
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ModemControl
+{
+    pub dtr: bool,
+    pub rts: bool,
+    pub out1: bool,
+    pub out2: bool,
+    pub loopback: bool
+}
+
+impl ModemControl
+{
+    /* convert to and from the modem control register's bit layout */
+    pub fn bits(&self) -> u8
+    {
+        let mut bits = 0;
+        if self.dtr { bits |= MODEM_CONTROL_DTR }
+        if self.rts { bits |= MODEM_CONTROL_RTS }
+        if self.out1 { bits |= MODEM_CONTROL_OUT1 }
+        if self.out2 { bits |= MODEM_CONTROL_OUT2 }
+        if self.loopback { bits |= MODEM_CONTROL_LOOP }
+        bits
+    }
+
+    pub fn from_bits(bits: u8) -> Self
+    {
+        ModemControl
+        {
+            dtr: bits & MODEM_CONTROL_DTR != 0,
+            rts: bits & MODEM_CONTROL_RTS != 0,
+            out1: bits & MODEM_CONTROL_OUT1 != 0,
+            out2: bits & MODEM_CONTROL_OUT2 != 0,
+            loopback: bits & MODEM_CONTROL_LOOP != 0
+        }
+    }
+}
+
+/* settings used to initialize a UART. start from Config::default(),
+   which matches the crate's original 38400 8-n-1 setup, and
+   adjust with the with_*() methods */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Config
+{
+    baud_rate: u32,
+    clock_hz: u32,
+    baud_tolerance_bp: u32,
+    reg_shift: u8,
+    access_width: AccessWidth,
+    data_bits: DataBits,
+    parity: Parity,
+    stop_bits: StopBits,
+    fifo_trigger: Option<FifoTrigger>,
+    modem_control: ModemControl,
+    interrupts: InterruptEnable,
+    tx_timeout: Timeout,
+    rx_timeout: Timeout,
+    lf_to_crlf: bool,
+    variant: Option<Variant>,
+    presence_check: bool,
+    flow_control: FlowControl,
+    shutdown_on_drop: bool
+}
+
+impl Default for Config
+{
+    fn default() -> Self
+    {
+        Config
+        {
+            baud_rate: DEFAULT_BAUD_RATE,
+            clock_hz: DEFAULT_CLOCK_HZ,
+            baud_tolerance_bp: DEFAULT_BAUD_TOLERANCE_BP,
+            reg_shift: 0,
+            access_width: AccessWidth::Bits8,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            fifo_trigger: Some(FifoTrigger::Bytes14),
+            modem_control: ModemControl { dtr: true, rts: true, out1: false, out2: true, loopback: false },
+            interrupts: InterruptEnable { rx_data: true, ..InterruptEnable::default() },
+            tx_timeout: Timeout::Spins(LOOP_MAX),
+            rx_timeout: Timeout::NonBlocking,
+            lf_to_crlf: false,
+            variant: None,
+            presence_check: true,
+            flow_control: FlowControl::None,
+            shutdown_on_drop: false
+        }
+    }
+}
+
+impl Config
+{
+    pub fn with_baud_rate(mut self, baud_rate: u32) -> Self
+    {
+        self.baud_rate = baud_rate;
+        self
+    }
+
+    /* frequency of the clock fed into the UART, from which the baud rate is derived */
+    pub fn with_clock_hz(mut self, clock_hz: u32) -> Self
+    {
+        self.clock_hz = clock_hz;
+        self
+    }
+
+    /* how far, in basis points (hundredths of a percent), the achieved
+       baud rate may stray from the requested rate before initialization fails */
+    pub fn with_baud_tolerance_bp(mut self, tolerance_bp: u32) -> Self
+    {
+        self.baud_tolerance_bp = tolerance_bp;
+        self
+    }
+
+    /* registers are (1 << reg_shift) bytes apart, eg 2 for a DesignWare APB UART with
+       its registers on 32-bit boundaries. UART creation fails with InvalidConfig for
+       shifts above MAX_REG_SHIFT */
+    pub fn with_reg_shift(mut self, reg_shift: u8) -> Self
+    {
+        self.reg_shift = reg_shift;
+        self
+    }
+
+    pub fn with_access_width(mut self, access_width: AccessWidth) -> Self
+    {
+        self.access_width = access_width;
+        self
+    }
+
+    pub fn with_data_bits(mut self, data_bits: DataBits) -> Self
+    {
+        self.data_bits = data_bits;
+        self
+    }
+
+    pub fn with_parity(mut self, parity: Parity) -> Self
+    {
+        self.parity = parity;
+        self
+    }
+
+    pub fn with_stop_bits(mut self, stop_bits: StopBits) -> Self
+    {
+        self.stop_bits = stop_bits;
+        self
+    }
+
+    pub fn with_fifo_trigger(mut self, trigger: FifoTrigger) -> Self
+    {
+        self.fifo_trigger = Some(trigger);
+        self
+    }
+
+    /* run the UART in 16450-compatible, byte-at-a-time mode */
+    pub fn without_fifo(mut self) -> Self
+    {
+        self.fifo_trigger = None;
+        self
+    }
+
+    pub fn with_modem_control(mut self, modem_control: ModemControl) -> Self
+    {
+        self.modem_control = modem_control;
+        self
+    }
+
+    /* interrupts the chip raises once initialized */
+    pub fn with_interrupts(mut self, interrupts: InterruptEnable) -> Self
+    {
+        self.interrupts = interrupts;
+        self
+    }
+
+    /* how long send_byte() waits for room to transmit */
+    pub fn with_tx_timeout(mut self, timeout: Timeout) -> Self
+    {
+        self.tx_timeout = timeout;
+        self
+    }
+
+    /* how long read_byte() waits for a byte to arrive */
+    pub fn with_rx_timeout(mut self, timeout: Timeout) -> Self
+    {
+        self.rx_timeout = timeout;
+        self
+    }
+
+    pub fn with_flow_control(mut self, flow_control: FlowControl) -> Self
+    {
+        self.flow_control = flow_control;
+        self
+    }
+
+    /* send CR before each LF in strings, as most terminals expect */
+    pub fn with_lf_to_crlf(mut self, lf_to_crlf: bool) -> Self
+    {
+        self.lf_to_crlf = lf_to_crlf;
+        self
+    }
+
+    /* drive the chip as the given variant rather than probing it during
+       initialization, eg if the part is known and probing would upset it */
+    pub fn with_variant(mut self, variant: Variant) -> Self
+    {
+        self.variant = Some(variant);
+        self
+    }
+
+    /* check that a 16550 responds before initializing it, failing with NoDevice
+       if not. on by default; turn it off for register backends that can't loop back */
+    pub fn with_presence_check(mut self, presence_check: bool) -> Self
+    {
+        self.presence_check = presence_check;
+        self
+    }
+
+    /* shut the chip down when its UART object is dropped. off by default, as a UART
+       used for a console or a debug port is usually best left as it is */
+    pub fn with_shutdown_on_drop(mut self, shutdown_on_drop: bool) -> Self
+    {
+        self.shutdown_on_drop = shutdown_on_drop;
+        self
+    }
+
+    pub fn baud_rate(&self) -> u32 { self.baud_rate }
+    pub fn clock_hz(&self) -> u32 { self.clock_hz }
+    pub fn baud_tolerance_bp(&self) -> u32 { self.baud_tolerance_bp }
+    pub fn reg_shift(&self) -> u8 { self.reg_shift }
+    pub fn access_width(&self) -> AccessWidth { self.access_width }
+    pub fn data_bits(&self) -> DataBits { self.data_bits }
+    pub fn parity(&self) -> Parity { self.parity }
+    pub fn stop_bits(&self) -> StopBits { self.stop_bits }
+    pub fn fifo_trigger(&self) -> Option<FifoTrigger> { self.fifo_trigger }
+    pub fn modem_control(&self) -> ModemControl { self.modem_control }
+    pub fn interrupts(&self) -> InterruptEnable { self.interrupts }
+    pub fn tx_timeout(&self) -> Timeout { self.tx_timeout }
+    pub fn rx_timeout(&self) -> Timeout { self.rx_timeout }
+    pub fn lf_to_crlf(&self) -> bool { self.lf_to_crlf }
+    pub fn flow_control(&self) -> FlowControl { self.flow_control }
+    pub fn variant(&self) -> Option<Variant> { self.variant }
+    pub fn presence_check(&self) -> bool { self.presence_check }
+    pub fn shutdown_on_drop(&self) -> bool { self.shutdown_on_drop }
+
+    /* return the number of bytes spanned by register_count registers laid out
+       using these settings, or fail if the registers would overlap */
+    pub(crate) fn register_span(&self, register_count: usize) -> Result<usize, Fault>
+    {
+        if self.reg_shift > MAX_REG_SHIFT { return Err(Fault::InvalidConfig) }
+
+        let stride = 1usize << self.reg_shift;
+        if self.access_width.bytes() > stride { return Err(Fault::InvalidConfig) }
+
+        register_count.checked_sub(1)
+            .and_then(|last| last.checked_mul(stride))
+            .and_then(|offset| offset.checked_add(self.access_width.bytes()))
+            .ok_or(Fault::InvalidConfig)
+    }
+
+    /* return the line control register value for these settings with DLAB clear,
+       or fail if the 16550 can't generate this combination of bits */
+    pub(crate) fn line_control(&self) -> Result<u8, Fault>
+    {
+        let mut lcr = match self.data_bits
+        {
+            DataBits::Five => 0b00,
+            DataBits::Six => 0b01,
+            DataBits::Seven => 0b10,
+            DataBits::Eight => 0b11
+        };
+
+        lcr |= match (self.stop_bits, self.data_bits)
+        {
+            (StopBits::One, _) => 0,
+            (StopBits::OnePointFive, DataBits::Five) => LINE_CONTROL_STOP_BITS,
+            (StopBits::Two, DataBits::Five) => return Err(Fault::InvalidConfig),
+            (StopBits::OnePointFive, _) => return Err(Fault::InvalidConfig),
+            (StopBits::Two, _) => LINE_CONTROL_STOP_BITS
+        };
+
+        lcr |= match self.parity
+        {
+            Parity::None => 0,
+            Parity::Odd => LINE_CONTROL_PARITY_EN,
+            Parity::Even => LINE_CONTROL_PARITY_EN | LINE_CONTROL_EVEN,
+            Parity::Mark => LINE_CONTROL_PARITY_EN | LINE_CONTROL_STICK,
+            Parity::Space => LINE_CONTROL_PARITY_EN | LINE_CONTROL_EVEN | LINE_CONTROL_STICK
+        };
+
+        Ok(lcr)
+    }
+
+    /* return the FIFO control register value for these settings,
+       resetting both FIFOs in the process */
+    pub(crate) fn fifo_control(&self) -> u8
+    {
+        match self.fifo_trigger
+        {
+            None => 0,
+            Some(trigger) =>
+            {
+                let level = match trigger
+                {
+                    FifoTrigger::Bytes1 => 0b00,
+                    FifoTrigger::Bytes4 => 0b01,
+                    FifoTrigger::Bytes8 => 0b10,
+                    FifoTrigger::Bytes14 => 0b11
+                };
+
+                (level << 6) | FIFO_CONTROL_TX_RESET | FIFO_CONTROL_RX_RESET | FIFO_CONTROL_ENABLE
+            }
+        }
+    }
+
+    /* return the divisor latch value closest to these settings' baud rate,
+       or fail if it's outside the allowed tolerance */
+    pub fn divisor(&self) -> Result<Divisor, Fault>
+    {
+        Divisor::calculate(self.clock_hz, self.baud_rate, self.baud_tolerance_bp)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn default_matches_original_setup()
+    {
+        let config = Config::default();
+        assert_eq!(config.line_control(), Ok(0b0011));
+        assert_eq!(config.divisor().map(|d| d.value()), Ok(3));
+        assert_eq!(config.fifo_control(), 0xc7);
+        assert_eq!(config.modem_control().bits(), 0b1011);
+    }
+
+    #[test]
+    fn line_control_bits()
+    {
+        let config = Config::default()
+            .with_data_bits(DataBits::Seven)
+            .with_parity(Parity::Even)
+            .with_stop_bits(StopBits::Two);
+        assert_eq!(config.line_control(), Ok(0b0001_1110));
+
+        let config = Config::default()
+            .with_data_bits(DataBits::Five)
+            .with_parity(Parity::Space)
+            .with_stop_bits(StopBits::OnePointFive);
+        assert_eq!(config.line_control(), Ok(0b0011_1100));
+    }
+
+    #[test]
+    fn impossible_stop_bits_rejected()
+    {
+        let config = Config::default().with_stop_bits(StopBits::OnePointFive);
+        assert_eq!(config.line_control(), Err(Fault::InvalidConfig));
+
+        let config = Config::default().with_data_bits(DataBits::Five).with_stop_bits(StopBits::Two);
+        assert_eq!(config.line_control(), Err(Fault::InvalidConfig));
+    }
+
+    #[test]
+    fn register_span()
+    {
+        assert_eq!(Config::default().register_span(8), Ok(8));
+
+        let config = Config::default().with_reg_shift(2).with_access_width(AccessWidth::Bits32);
+        assert_eq!(config.register_span(8), Ok(32));
+
+        let config = Config::default().with_reg_shift(2);
+        assert_eq!(config.register_span(8), Ok(29));
+
+        let config = Config::default().with_access_width(AccessWidth::Bits16);
+        assert_eq!(config.register_span(8), Err(Fault::InvalidConfig));
+
+        let config = Config::default().with_reg_shift(MAX_REG_SHIFT + 1);
+        assert_eq!(config.register_span(8), Err(Fault::InvalidConfig));
+        assert_eq!(Config::default().with_reg_shift(62).register_span(8), Err(Fault::InvalidConfig));
+    }
+
+    #[test]
+    fn fifo_disabled()
+    {
+        assert_eq!(Config::default().without_fifo().fifo_control(), 0);
+        assert_eq!(Config::default().with_fifo_trigger(FifoTrigger::Bytes4).fifo_control(), 0x47);
+    }
+}
@@ -0,0 +1,179 @@
+/* format text to a UART, and to a global console for print-style macros
+ *
+ * Early boot code can register a UART as the console once it's initialized,
+ * and then use console_print!() and console_println!() like print!() and println!():
+ *
+ *   let uart: &'static UART = ...;
+ *   mmio_16550_uart::set_console(uart)?;
+ *   console_println!("booting on hart {}", hartid);
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See README and LICENSE for usage and copying.
+ */
+
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
+use super::*;
+
+/* states of the global console slot */
+const CONSOLE_EMPTY: u8 = 0;
+const CONSOLE_SETTING: u8 = 1;
+const CONSOLE_READY: u8 = 2;
+
+/* anything that can output text on behalf of the print macros */
+pub trait Console: Sync
+{
+    fn send_str(&self, s: &str) -> Result<(), Fault>;
+}
+
+impl<R: Registers + Sync> Console for UART<R>
+{
+    fn send_str(&self, s: &str) -> Result<(), Fault>
+    {
+        UART::send_str(self, s)
+    }
+}
+
+impl<R: Registers> UART<R>
+{
+    /* transmit a string, inserting a CR before each LF if configured to */
+    pub fn send_str(&self, s: &str) -> Result<(), Fault>
+    {
+        for byte in s.bytes()
+        {
+            if byte == b'\n' && self.lf_to_crlf { self.send_byte(b'\r')? }
+            self.send_byte(byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl<R: Registers> fmt::Write for UART<R>
+{
+    fn write_str(&mut self, s: &str) -> fmt::Result
+    {
+        self.send_str(s).map_err(|_| fmt::Error)
+    }
+}
+
+/* the global console can be set once. after that it's only read */
+struct ConsoleSlot
+{
+    state: AtomicU8,
+    console: UnsafeCell<Option<&'static dyn Console>>
+}
+
+/* the console reference is only written while state is CONSOLE_SETTING,
+   by the one caller that moved it there, and only read once it's CONSOLE_READY */
+unsafe impl Sync for ConsoleSlot {}
+
+static CONSOLE: ConsoleSlot = ConsoleSlot
+{
+    state: AtomicU8::new(CONSOLE_EMPTY),
+    console: UnsafeCell::new(None)
+};
+
+/* make console the target of console_print!() and console_println!().
+   fails with ConsoleAlreadySet if a console has already been registered */
+pub fn set_console(console: &'static dyn Console) -> Result<(), Fault>
+{
+    if CONSOLE.state.compare_exchange(CONSOLE_EMPTY, CONSOLE_SETTING, Ordering::Acquire, Ordering::Relaxed).is_err()
+    {
+        return Err(Fault::ConsoleAlreadySet);
+    }
+
+    unsafe { *CONSOLE.console.get() = Some(console) }
+    CONSOLE.state.store(CONSOLE_READY, Ordering::Release);
+    Ok(())
+}
+
+fn console() -> Option<&'static dyn Console>
+{
+    if CONSOLE.state.load(Ordering::Acquire) != CONSOLE_READY { return None }
+    unsafe { *CONSOLE.console.get() }
+}
+
+/* adapt a console to the formatting machinery */
+struct ConsoleWriter(&'static dyn Console);
+
+impl fmt::Write for ConsoleWriter
+{
+    fn write_str(&mut self, s: &str) -> fmt::Result
+    {
+        self.0.send_str(s).map_err(|_| fmt::Error)
+    }
+}
+
+/* used by the print macros. output is discarded if there's no console yet */
+#[doc(hidden)]
+pub fn print_to_console(args: fmt::Arguments<'_>)
+{
+    if let Some(console) = console()
+    {
+        let _ = fmt::write(&mut ConsoleWriter(console), args);
+    }
+}
+
+/* print to the registered console, if any */
+#[macro_export]
+macro_rules! console_print
+{
+    ($($arg:tt)*) => ($crate::print_to_console(format_args!($($arg)*)));
+}
+
+/* print a line to the registered console, if any */
+#[macro_export]
+macro_rules! console_println
+{
+    () => ($crate::console_print!("\n"));
+    ($($arg:tt)*) => ($crate::print_to_console(format_args!("{}\n", format_args!($($arg)*))));
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use core::fmt::Write;
+    use std::sync::Mutex;
+    use model::Model;
+
+    #[test]
+    fn format_to_uart()
+    {
+        let model = Model::new();
+        let mut uart = UART::with_registers(&model, Config::default().with_lf_to_crlf(true)).unwrap();
+
+        writeln!(uart, "{}+{}", 1, 2).unwrap();
+        let sent: Vec<u8> = core::iter::from_fn(|| model.take_tx()).collect();
+        assert_eq!(sent, b"1+2\r\n");
+    }
+
+    /* collect the global console's output */
+    struct Recorder(Mutex<String>);
+
+    impl Console for Recorder
+    {
+        fn send_str(&self, s: &str) -> Result<(), Fault>
+        {
+            self.0.lock().unwrap().push_str(s);
+            Ok(())
+        }
+    }
+
+    static RECORDER: Recorder = Recorder(Mutex::new(String::new()));
+
+    #[test]
+    fn global_console()
+    {
+        console_println!("lost");
+        assert_eq!(set_console(&RECORDER), Ok(()));
+        assert_eq!(set_console(&RECORDER), Err(Fault::ConsoleAlreadySet));
+
+        console_print!("{:x}", 255);
+        console_println!();
+        console_println!("{} {}", "hello", 'w');
+        assert_eq!(*RECORDER.0.lock().unwrap(), "ff\nhello w\n");
+    }
+}
@@ -0,0 +1,380 @@
+/* the inside of a 16550, shared by the software model and the virtual device
+ *
+ * This implements the full register file, including the divisor latches
+ * behind DLAB, 16-byte RX and TX FIFOs with trigger levels, internal loopback,
+ * line status errors, modem status changes, and interrupt identification.
+ * Bytes transmitted are held until collected, and while there's no room to
+ * hold them they stay in the TX FIFO, with THRE clear, as if the line were busy.
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See README and LICENSE for usage and copying.
+ */
+
+use super::*;
+
+const WIRE_DEPTH: usize = 256;  /* transmitted bytes held until collected with take_tx() */
+
+/* FCR bits kept by the chip: the enable bit and the RX trigger level */
+const FIFO_CONTROL_KEPT: u8 = FIFO_CONTROL_ENABLE | (0b11 << 6);
+
+/* fixed-size first-in-first-out queue */
+struct Fifo<T: Copy + Default, const N: usize>
+{
+    slots: [T; N],
+    head: usize,
+    len: usize
+}
+
+impl<T: Copy + Default, const N: usize> Fifo<T, N>
+{
+    fn new() -> Self
+    {
+        Fifo { slots: [T::default(); N], head: 0, len: 0 }
+    }
+
+    /* add an item to the end of the queue, or return false if there's no room */
+    fn push(&mut self, item: T) -> bool
+    {
+        if self.len == N { return false }
+        self.slots[(self.head + self.len) % N] = item;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<T>
+    {
+        let item = self.peek()?;
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(item)
+    }
+
+    fn peek(&self) -> Option<T>
+    {
+        if self.len == 0 { return None }
+        Some(self.slots[self.head])
+    }
+
+    fn peek_mut(&mut self) -> Option<&mut T>
+    {
+        if self.len == 0 { return None }
+        Some(&mut self.slots[self.head])
+    }
+
+    fn iter(&self) -> impl Iterator<Item = T> + '_
+    {
+        (0..self.len).map(move |i| self.slots[(self.head + i) % N])
+    }
+
+    fn len(&self) -> usize { self.len }
+    fn is_empty(&self) -> bool { self.len == 0 }
+    fn clear(&mut self) { self.len = 0 }
+}
+
+/* the chip's registers, FIFOs and interrupt logic */
+pub(crate) struct Device
+{
+    rx: Fifo<(u8, u8), FIFO_DEPTH>,     /* received bytes and their line status error bits */
+    tx: Fifo<u8, FIFO_DEPTH>,           /* bytes waiting to be transmitted */
+    wire: Fifo<u8, WIRE_DEPTH>,         /* bytes transmitted */
+    irq_enable: u8,
+    line_control: u8,
+    modem_control: u8,
+    fifo_control: u8,
+    divisor_lsb: u8,
+    divisor_msb: u8,
+    scratch: u8,
+    overrun: bool,                      /* a received byte was dropped since LSR was last read */
+    tx_empty_irq: bool,                 /* THRE interrupt pending */
+    rx_timeout: bool,                   /* character timeout interrupt pending */
+    modem_inputs: u8,                   /* CTS, DSR, RI and DCD as driven by the other end */
+    modem_deltas: u8,                   /* changes to the modem status since MSR was last read */
+    tx_stalled: bool                    /* hold transmitted bytes in the TX FIFO */
+}
+
+impl Device
+{
+    /* create a device in its power-on state */
+    pub(crate) fn new() -> Self
+    {
+        Device
+        {
+            rx: Fifo::new(),
+            tx: Fifo::new(),
+            wire: Fifo::new(),
+            irq_enable: 0,
+            line_control: 0,
+            modem_control: 0,
+            fifo_control: 0,
+            divisor_lsb: 0,
+            divisor_msb: 0,
+            scratch: 0,
+            overrun: false,
+            tx_empty_irq: false,
+            rx_timeout: false,
+            modem_inputs: 0,
+            modem_deltas: 0,
+            tx_stalled: false
+        }
+    }
+
+    fn dlab(&self) -> bool { self.line_control & LINE_CONTROL_DLAB != 0 }
+    pub(crate) fn fifo_enabled(&self) -> bool { self.fifo_control & FIFO_CONTROL_ENABLE != 0 }
+    fn loopback(&self) -> bool { ModemControl::from_bits(self.modem_control).loopback }
+
+    /* number of bytes the RX side can hold: the FIFO, or just the holding register */
+    pub(crate) fn rx_capacity(&self) -> usize
+    {
+        if self.fifo_enabled() { FIFO_DEPTH } else { 1 }
+    }
+
+    /* number of bytes in the RX FIFO that raise the RX data interrupt */
+    fn rx_trigger(&self) -> usize
+    {
+        if !self.fifo_enabled() { return 1 }
+        match self.fifo_control >> 6
+        {
+            0b00 => 1,
+            0b01 => 4,
+            0b10 => 8,
+            _ => 14
+        }
+    }
+
+    /* a byte has arrived, either from the wire or looped back */
+    pub(crate) fn receive(&mut self, byte: u8, errors: u8)
+    {
+        if self.rx.len() >= self.rx_capacity() || !self.rx.push((byte, errors))
+        {
+            self.overrun = true;
+        }
+    }
+
+    /* the CPU has written a byte to the transmit holding register */
+    fn transmit(&mut self, byte: u8)
+    {
+        if self.loopback()
+        {
+            self.receive(byte, 0);
+        }
+        else if self.line_control & LINE_CONTROL_BREAK != 0
+        {
+            /* the line is held low, so the byte never makes it out */
+        }
+        else if self.tx_stalled || self.wire.len() == WIRE_DEPTH || !self.tx.is_empty()
+        {
+            /* without a FIFO, a byte written while THR is full overwrites it */
+            if !self.fifo_enabled() { self.tx.clear() }
+            self.tx.push(byte);
+            self.tx_empty_irq = false;
+            return;
+        }
+        else
+        {
+            self.wire.push(byte);
+        }
+
+        /* the byte has gone straight out, so THR is immediately empty again */
+        self.tx_empty_irq = true;
+    }
+
+    /* move held bytes out onto the wire as far as there's room */
+    fn drain_tx(&mut self)
+    {
+        if self.tx_stalled { return }
+
+        let mut drained = false;
+        while self.wire.len() < WIRE_DEPTH
+        {
+            match self.tx.pop()
+            {
+                Some(byte) => drained = self.wire.push(byte),
+                None => break
+            }
+        }
+        if drained && self.tx.is_empty() { self.tx_empty_irq = true }
+    }
+
+    fn line_status(&self) -> u8
+    {
+        let mut status = 0;
+        if let Some((_, errors)) = self.rx.peek() { status |= LINE_STATUS_DR | errors }
+        if self.overrun { status |= LINE_STATUS_OE }
+        if self.tx.is_empty() { status |= LINE_STATUS_THRE | LINE_STATUS_TEMT }
+        if self.fifo_enabled() && self.rx.iter().any(|(_, errors)| errors != 0)
+        {
+            status |= LINE_STATUS_RXFE;
+        }
+        status
+    }
+
+    /* current state of the modem input lines, which loop back from MCR in loopback mode */
+    fn modem_lines(&self) -> u8
+    {
+        if !self.loopback() { return self.modem_inputs }
+
+        let mcr = ModemControl::from_bits(self.modem_control);
+        let mut lines = 0;
+        if mcr.rts { lines |= MODEM_STATUS_CTS }
+        if mcr.dtr { lines |= MODEM_STATUS_DSR }
+        if mcr.out1 { lines |= MODEM_STATUS_RI }
+        if mcr.out2 { lines |= MODEM_STATUS_DCD }
+        lines
+    }
+
+    /* record any changes in the modem input lines as delta bits */
+    fn update_modem_deltas(&mut self, before: u8)
+    {
+        let after = self.modem_lines();
+        let changed = before ^ after;
+        if changed & MODEM_STATUS_CTS != 0 { self.modem_deltas |= MODEM_STATUS_DCTS }
+        if changed & MODEM_STATUS_DSR != 0 { self.modem_deltas |= MODEM_STATUS_DDSR }
+        if changed & MODEM_STATUS_DCD != 0 { self.modem_deltas |= MODEM_STATUS_DDCD }
+        if before & !after & MODEM_STATUS_RI != 0 { self.modem_deltas |= MODEM_STATUS_TERI }
+    }
+
+    /* return the highest priority pending interrupt */
+    fn irq_id(&self) -> u8
+    {
+        let line_errors = self.line_status() & (LINE_STATUS_OE | LINE_STATUS_BYTE_ERRORS);
+
+        if self.irq_enable & IRQ_EN_LINE_STATUS != 0 && line_errors != 0
+        {
+            return IRQ_ID_LINE_STATUS;
+        }
+        if self.irq_enable & IRQ_EN_RX_DATA != 0
+        {
+            if self.rx.len() >= self.rx_trigger() { return IRQ_ID_RX_DATA }
+            if self.rx_timeout && !self.rx.is_empty() { return IRQ_ID_RX_TIMEOUT }
+        }
+        if self.irq_enable & IRQ_EN_TX_EMPTY != 0 && self.tx_empty_irq
+        {
+            return IRQ_ID_TX_EMPTY;
+        }
+        if self.irq_enable & IRQ_EN_MODEM_STATUS != 0 && self.modem_deltas != 0
+        {
+            return IRQ_ID_MODEM_STATUS;
+        }
+        IRQ_ID_NONE
+    }
+
+    pub(crate) fn load(&mut self, reg: usize) -> u8
+    {
+        match reg
+        {
+            REG_DIVISOR_LSB if self.dlab() => self.divisor_lsb,
+            REG_DIVISOR_MSB if self.dlab() => self.divisor_msb,
+            REG_DATA =>
+            {
+                self.rx_timeout = false;
+                self.rx.pop().map(|(byte, _)| byte).unwrap_or(0)
+            },
+            REG_IRQ_EN => self.irq_enable,
+            REG_IRQ_ID =>
+            {
+                /* reading IIR acknowledges a THRE interrupt */
+                let id = self.irq_id();
+                if id == IRQ_ID_TX_EMPTY { self.tx_empty_irq = false }
+                if self.fifo_enabled() { id | IRQ_ID_FIFOS_ENABLED } else { id }
+            },
+            REG_LINE_CONTROL => self.line_control,
+            REG_MODEM_CONTROL => self.modem_control,
+            REG_LINE_STATUS =>
+            {
+                /* reading LSR clears the errors it reports, acknowledging
+                   any receiver line status interrupt */
+                let status = self.line_status();
+                self.overrun = false;
+                if let Some((_, errors)) = self.rx.peek_mut() { *errors = 0 }
+                status
+            },
+            REG_MODEM_STATUS =>
+            {
+                let status = self.modem_lines() | self.modem_deltas;
+                self.modem_deltas = 0;
+                status
+            },
+            REG_SCRATCH => self.scratch,
+            _ => 0xff
+        }
+    }
+
+    pub(crate) fn store(&mut self, reg: usize, val: u8)
+    {
+        match reg
+        {
+            REG_DIVISOR_LSB if self.dlab() => self.divisor_lsb = val,
+            REG_DIVISOR_MSB if self.dlab() => self.divisor_msb = val,
+            REG_DATA => self.transmit(val),
+            REG_IRQ_EN =>
+            {
+                /* enabling the THRE interrupt while THR is empty raises it straight away */
+                let enabling = !self.irq_enable & val & IRQ_EN_TX_EMPTY != 0;
+                self.irq_enable = val & 0x0f;
+                if enabling && self.tx.is_empty() { self.tx_empty_irq = true }
+            },
+            REG_FIFO_CONTROL =>
+            {
+                let enable = val & FIFO_CONTROL_ENABLE != 0;
+                if enable != self.fifo_enabled()
+                {
+                    self.rx.clear();
+                    self.tx.clear();
+                }
+                if val & FIFO_CONTROL_RX_RESET != 0
+                {
+                    self.rx.clear();
+                    self.rx_timeout = false;
+                }
+                if val & FIFO_CONTROL_TX_RESET != 0 { self.tx.clear() }
+                self.fifo_control = if enable { val & FIFO_CONTROL_KEPT } else { 0 };
+            },
+            REG_LINE_CONTROL => self.line_control = val,
+            REG_MODEM_CONTROL =>
+            {
+                let before = self.modem_lines();
+                self.modem_control = val & 0x1f;
+                self.update_modem_deltas(before);
+            },
+            REG_SCRATCH => self.scratch = val,
+            _ => ()
+        }
+    }
+
+    /* no more bytes have arrived for a while, so raise the character timeout
+       interrupt if there are bytes in the RX FIFO below the trigger level */
+    pub(crate) fn character_timeout(&mut self)
+    {
+        self.rx_timeout = !self.rx.is_empty();
+    }
+
+    /* collect the next transmitted byte, making room for any held back in the TX FIFO */
+    pub(crate) fn take_tx(&mut self) -> Option<u8>
+    {
+        let byte = self.wire.pop();
+        self.drain_tx();
+        byte
+    }
+
+    pub(crate) fn set_tx_stalled(&mut self, stalled: bool)
+    {
+        self.tx_stalled = stalled;
+        self.drain_tx();
+    }
+
+    /* drive the modem input lines, given as MSR bits */
+    pub(crate) fn set_modem_inputs(&mut self, lines: u8)
+    {
+        let before = self.modem_lines();
+        self.modem_inputs = lines & (MODEM_STATUS_CTS | MODEM_STATUS_DSR | MODEM_STATUS_RI | MODEM_STATUS_DCD);
+        self.update_modem_deltas(before);
+    }
+
+    pub(crate) fn interrupt_pending(&self) -> bool { self.irq_id() != IRQ_ID_NONE }
+    pub(crate) fn divisor(&self) -> u16 { (self.divisor_msb as u16) << 8 | self.divisor_lsb as u16 }
+    pub(crate) fn line_control(&self) -> u8 { self.line_control }
+    pub(crate) fn modem_control(&self) -> ModemControl { ModemControl::from_bits(self.modem_control) }
+    pub(crate) fn interrupt_enable(&self) -> u8 { self.irq_enable }
+    pub(crate) fn rx_len(&self) -> usize { self.rx.len() }
+}
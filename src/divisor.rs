@@ -0,0 +1,114 @@
+/* calculate the 16550's baud rate divisor from its input clock
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See README and LICENSE for usage and copying.
+ */
+
+use super::Fault;
+
+/* the UART samples each bit this many times, so the
+   baud rate is the input clock / (16 * divisor) */
+const SAMPLES_PER_BIT: u64 = 16;
+
+/* reject divisors that miss the requested baud rate by more than this,
+   in basis points (hundredths of a percent): 250 = 2.5% */
+pub const DEFAULT_BAUD_TOLERANCE_BP: u32 = 250;
+
+/* a divisor latch value and the baud rate it actually produces */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Divisor
+{
+    value: u16,
+    actual_baud: u32,
+    error_bp: i32
+}
+
+impl Divisor
+{
+    /* pick the closest divisor to generate baud_rate from a UART fed by clock_hz.
+       fail with InvalidConfig if either is zero, or BaudRateUnachievable if the
+       closest divisor misses the requested rate by more than tolerance_bp basis points */
+    pub fn calculate(clock_hz: u32, baud_rate: u32, tolerance_bp: u32) -> Result<Self, Fault>
+    {
+        if clock_hz == 0 || baud_rate == 0 { return Err(Fault::InvalidConfig) }
+
+        /* round to the nearest divisor and keep it within the latch's 16 bits */
+        let ticks = baud_rate as u64 * SAMPLES_PER_BIT;
+        let value = (clock_hz as u64 + ticks / 2) / ticks;
+        let value = value.max(1).min(u16::MAX as u64);
+
+        /* work out what we'll really get, and how far off that is */
+        let actual = clock_hz as u64 / (SAMPLES_PER_BIT * value);
+        let difference = actual as i64 - baud_rate as i64;
+        let error_bp = (difference * 10000) / baud_rate as i64;
+
+        if error_bp.unsigned_abs() > tolerance_bp as u64
+        {
+            return Err(Fault::BaudRateUnachievable);
+        }
+
+        Ok(Divisor
+        {
+            value: value as u16,
+            actual_baud: actual as u32,
+            error_bp: error_bp as i32
+        })
+    }
+
+    /* the value to program into the divisor latch */
+    pub fn value(&self) -> u16 { self.value }
+
+    /* the baud rate this divisor generates */
+    pub fn actual_baud(&self) -> u32 { self.actual_baud }
+
+    /* how far the actual baud rate is from the requested rate in basis points,
+       positive if faster, negative if slower. 100 = 1% */
+    pub fn error_bp(&self) -> i32 { self.error_bp }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn exact_divisor()
+    {
+        let divisor = Divisor::calculate(1_843_200, 38400, DEFAULT_BAUD_TOLERANCE_BP).unwrap();
+        assert_eq!(divisor.value(), 3);
+        assert_eq!(divisor.actual_baud(), 38400);
+        assert_eq!(divisor.error_bp(), 0);
+    }
+
+    #[test]
+    fn closest_divisor_and_error()
+    {
+        /* 24 MHz / (16 * 115200) = 13.02 */
+        let divisor = Divisor::calculate(24_000_000, 115200, DEFAULT_BAUD_TOLERANCE_BP).unwrap();
+        assert_eq!(divisor.value(), 13);
+        assert_eq!(divisor.actual_baud(), 115384);
+        assert_eq!(divisor.error_bp(), 15);
+
+        /* 50 MHz / (16 * 115200) = 27.13 */
+        let divisor = Divisor::calculate(50_000_000, 115200, DEFAULT_BAUD_TOLERANCE_BP).unwrap();
+        assert_eq!(divisor.value(), 27);
+        assert_eq!(divisor.error_bp(), 46);
+    }
+
+    #[test]
+    fn out_of_tolerance()
+    {
+        /* 1.8432 MHz can only manage 115200 at best */
+        assert_eq!(Divisor::calculate(1_843_200, 230400, DEFAULT_BAUD_TOLERANCE_BP), Err(Fault::BaudRateUnachievable));
+
+        /* 100 MHz / (16 * 921600) = 6.78, so 7 is 3% slow */
+        assert_eq!(Divisor::calculate(100_000_000, 921600, DEFAULT_BAUD_TOLERANCE_BP), Err(Fault::BaudRateUnachievable));
+        assert!(Divisor::calculate(100_000_000, 921600, 400).is_ok());
+
+        /* too slow for the 16-bit latch */
+        assert_eq!(Divisor::calculate(100_000_000, 50, DEFAULT_BAUD_TOLERANCE_BP), Err(Fault::BaudRateUnachievable));
+
+        assert_eq!(Divisor::calculate(0, 9600, DEFAULT_BAUD_TOLERANCE_BP), Err(Fault::InvalidConfig));
+    }
+}
@@ -0,0 +1,597 @@
+/* find 16550s described by a flattened device tree
+ *
+ * Firmware and bootloaders describe the hardware to the OS in a devicetree
+ * blob (DTB). uarts() lists the enabled serial ports this driver can handle,
+ * each with the settings needed to drive it, and console() picks out the one
+ * named by /chosen/stdout-path, with any baud rate and framing given there:
+ *
+ *   let tree = DeviceTree::new(dtb)?;
+ *   let console = tree.console().ok_or(Fault::NoDevice)?.uart()?;
+ *
+ * reg is taken to be a CPU physical address: ranges properties that
+ * translate bus addresses aren't followed. Nor are the phandles of clocks
+ * and interrupt controllers, so a port's input clock is only known if it has
+ * a clock-frequency property, and its interrupts are left for the caller to decode.
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See README and LICENSE for usage and copying.
+ */
+
+use core::convert::TryFrom;
+use super::*;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_VERSION: u32 = 17;
+
+/* structure block tokens */
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+const MAX_DEPTH: usize = 16;            /* deepest node nesting handled */
+const DEFAULT_CELLS: (u32, u32) = (2, 1);   /* #address-cells and #size-cells if a node doesn't say */
+
+/* compatible strings of nodes this driver can handle */
+const COMPATIBLE: [&str; 8] =
+[
+    "ns8250", "ns16450", "ns16550", "ns16550a", "ns16650", "ns16750", "ns16850", "snps,dw-apb-uart"
+];
+
+/* a devicetree blob, checked to be well-formed */
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceTree<'a>
+{
+    structs: &'a [u8],
+    strings: &'a [u8]
+}
+
+impl<'a> DeviceTree<'a>
+{
+    /* check over a DTB, failing with BadDeviceTree if it's not one this code can read */
+    pub fn new(blob: &'a [u8]) -> Result<Self, Fault>
+    {
+        let field = |index: usize| be32(blob, index * 4).map(|val| val as usize).ok_or(Fault::BadDeviceTree);
+        let block = |offset: usize, size: usize| offset.checked_add(size).and_then(|end| blob.get(offset..end)).ok_or(Fault::BadDeviceTree);
+
+        if be32(blob, 0) != Some(FDT_MAGIC) || field(1)? > blob.len() { return Err(Fault::BadDeviceTree) }
+        if field(5)? < FDT_VERSION as usize || field(6)? > FDT_VERSION as usize { return Err(Fault::BadDeviceTree) }
+
+        let tree = DeviceTree { structs: block(field(2)?, field(9)?)?, strings: block(field(3)?, field(8)?)? };
+
+        /* walk the structure block once, so nothing later needs to worry about it being malformed */
+        let mut cursor = tree.cursor();
+        let mut depth = 0;
+        loop
+        {
+            match cursor.next()
+            {
+                Some(Token::BeginNode(_)) if depth < MAX_DEPTH => depth += 1,
+                Some(Token::EndNode) if depth > 0 => depth -= 1,
+                Some(Token::Prop(_, _)) if depth > 0 => (),
+                Some(Token::End) if depth == 0 => return Ok(tree),
+                _ => return Err(Fault::BadDeviceTree)
+            }
+        }
+    }
+
+    /* list the enabled serial ports compatible with this driver */
+    pub fn uarts(&self) -> impl Iterator<Item = FdtUart<'a>>
+    {
+        self.nodes().filter_map(|node| FdtUart::from_node(&node))
+    }
+
+    /* return the serial port named by /chosen/stdout-path, if it's one this driver can handle.
+       baud rate, parity, data bits and RTS/CTS flow control given after the path, as in
+       "serial0:115200n8r", override the node's own settings */
+    pub fn console(&self) -> Option<FdtUart<'a>>
+    {
+        let chosen = self.node("/chosen")?;
+        let stdout = chosen.property_str("stdout-path").or_else(|| chosen.property_str("linux,stdout-path"))?;
+        let (path, options) = match stdout.find(':')
+        {
+            Some(colon) => (&stdout[..colon], &stdout[colon + 1..]),
+            None => (stdout, "")
+        };
+
+        /* the path may start with an alias */
+        let path = if path.starts_with('/') { path } else { self.node("/aliases")?.property_str(path)? };
+
+        FdtUart::from_node(&self.node(path)?).map(|uart| uart.with_options(options))
+    }
+
+    fn node(&self, path: &str) -> Option<Node<'a>>
+    {
+        self.nodes().find(|node| node.has_path(path))
+    }
+
+    fn nodes(&self) -> Nodes<'a>
+    {
+        Nodes { cursor: self.cursor(), path: [""; MAX_DEPTH], cells: [DEFAULT_CELLS; MAX_DEPTH], depth: 0, current: None }
+    }
+
+    fn cursor(&self) -> Cursor<'a>
+    {
+        Cursor { structs: self.structs, strings: self.strings, offset: 0 }
+    }
+}
+
+/* a serial port found in the devicetree, and the settings to drive it with */
+#[derive(Debug, Clone, Copy)]
+pub struct FdtUart<'a>
+{
+    name: &'a str,
+    base_addr: usize,
+    size: usize,
+    interrupts: &'a [u8],   /* the interrupts property, or empty if there isn't one */
+    clock_hz: Option<u32>,
+    config: Config
+}
+
+impl<'a> FdtUart<'a>
+{
+    /* create and initialize a UART object for this port, or fail with InvalidConfig if its
+       input clock isn't known. in that case, give config() the clock and use UART::with_config() */
+    pub fn uart(&self) -> Result<UART, Fault>
+    {
+        if self.clock_hz.is_none() { return Err(Fault::InvalidConfig) }
+        UART::with_config(self.base_addr, self.size, self.config)
+    }
+
+    pub fn name(&self) -> &'a str { self.name }
+    pub fn base_addr(&self) -> usize { self.base_addr }
+    pub fn size(&self) -> usize { self.size }
+
+    /* the port's input clock from its clock-frequency property, or None if it's described some
+       other way, eg by a clocks property. config() only has the right clock if this is known */
+    pub fn clock_hz(&self) -> Option<u32> { self.clock_hz }
+    pub fn config(&self) -> Config { self.config }
+
+    /* the cells of the interrupts property, for the caller to decode as its interrupt controller
+       does, eg <irq> for a RISC-V PLIC or <type irq flags> for an Arm GIC */
+    pub fn interrupt_cells(&self) -> impl Iterator<Item = u32> + 'a
+    {
+        let interrupts = self.interrupts;
+        (0..interrupts.len() / 4).filter_map(move |cell| be32(interrupts, cell * 4))
+    }
+
+    /* describe the port a node describes, or return None if this driver can't handle it */
+    fn from_node(node: &Node<'a>) -> Option<Self>
+    {
+        let compatible = node.property("compatible")?;
+        if !compatible.split(|byte| *byte == 0).any(|name| COMPATIBLE.iter().any(|known| known.as_bytes() == name))
+        {
+            return None;
+        }
+
+        match node.property_str("status")
+        {
+            None | Some("okay") | Some("ok") => (),
+            Some(_) => return None
+        }
+
+        let mut config = Config::default();
+        if let Some(shift) = node.property_u32("reg-shift") { config = config.with_reg_shift(u8::try_from(shift).ok()?) }
+        if let Some(width) = node.property_u32("reg-io-width")
+        {
+            config = config.with_access_width(match width
+            {
+                1 => AccessWidth::Bits8,
+                2 => AccessWidth::Bits16,
+                4 => AccessWidth::Bits32,
+                _ => return None
+            });
+        }
+        let clock_hz = node.property_u32("clock-frequency");
+        if let Some(clock_hz) = clock_hz { config = config.with_clock_hz(clock_hz) }
+        if let Some(baud_rate) = node.property_u32("current-speed") { config = config.with_baud_rate(baud_rate) }
+
+        /* the first address and size in reg, laid out according to the parent node */
+        let (address_cells, size_cells) = node.cells;
+        let reg = node.property("reg")?;
+        let base_addr = usize::try_from(read_cells(reg, 0, address_cells)?).ok()?;
+        let size = match size_cells
+        {
+            0 => config.register_span(REG_COUNT).ok()?,
+            _ => usize::try_from(read_cells(reg, address_cells as usize, size_cells)?).ok()?
+        };
+
+        let interrupts = node.property("interrupts").unwrap_or(&[]);
+        Some(FdtUart { name: node.name(), base_addr, size, interrupts, clock_hz, config })
+    }
+
+    /* apply the options from a stdout-path, eg 115200n8 */
+    fn with_options(mut self, options: &str) -> Self
+    {
+        let digits = options.bytes().take_while(u8::is_ascii_digit).count();
+        if let Ok(baud_rate) = options[..digits].parse() { self.config = self.config.with_baud_rate(baud_rate) }
+
+        let mut rest = options[digits..].bytes();
+        let parity = match rest.next()
+        {
+            Some(b'n') => Parity::None,
+            Some(b'o') => Parity::Odd,
+            Some(b'e') => Parity::Even,
+            _ => return self
+        };
+        self.config = self.config.with_parity(parity);
+
+        let data_bits = match rest.next()
+        {
+            Some(b'5') => DataBits::Five,
+            Some(b'6') => DataBits::Six,
+            Some(b'7') => DataBits::Seven,
+            Some(b'8') => DataBits::Eight,
+            _ => return self
+        };
+        self.config = self.config.with_data_bits(data_bits);
+
+        if rest.next() == Some(b'r') { self.config = self.config.with_flow_control(FlowControl::RtsCts) }
+        self
+    }
+}
+
+/* pieces of the structure block */
+enum Token<'a>
+{
+    BeginNode(&'a str),
+    EndNode,
+    Prop(&'a str, &'a [u8]),
+    End
+}
+
+/* position in the structure block */
+#[derive(Clone)]
+struct Cursor<'a>
+{
+    structs: &'a [u8],
+    strings: &'a [u8],
+    offset: usize
+}
+
+impl<'a> Cursor<'a>
+{
+    /* return the next token, skipping NOPs, or None if the block is malformed */
+    fn next(&mut self) -> Option<Token<'a>>
+    {
+        loop
+        {
+            let token = be32(self.structs, self.offset)?;
+            self.offset += 4;
+
+            match token
+            {
+                FDT_BEGIN_NODE =>
+                {
+                    let name = c_str(self.structs.get(self.offset..)?)?;
+                    self.offset = align4(self.offset + name.len() + 1);
+                    return Some(Token::BeginNode(name));
+                },
+                FDT_END_NODE => return Some(Token::EndNode),
+                FDT_PROP =>
+                {
+                    let len = be32(self.structs, self.offset)? as usize;
+                    let name = c_str(self.strings.get(be32(self.structs, self.offset + 4)? as usize..)?)?;
+                    let start = self.offset + 8;
+                    let value = self.structs.get(start..start.checked_add(len)?)?;
+                    self.offset = align4(start + len);
+                    return Some(Token::Prop(name, value));
+                },
+                FDT_NOP => (),
+                FDT_END => return Some(Token::End),
+                _ => return None
+            }
+        }
+    }
+}
+
+/* a node, once all its properties have been seen */
+struct Node<'a>
+{
+    path: [&'a str; MAX_DEPTH],     /* names of the nodes from the root down to this one */
+    depth: usize,
+    cells: (u32, u32),              /* the parent's #address-cells and #size-cells */
+    props: Cursor<'a>               /* at this node's first property */
+}
+
+impl<'a> Node<'a>
+{
+    fn name(&self) -> &'a str
+    {
+        self.path[self.depth - 1]
+    }
+
+    fn property(&self, name: &str) -> Option<&'a [u8]>
+    {
+        let mut cursor = self.props.clone();
+        while let Some(Token::Prop(prop, value)) = cursor.next()
+        {
+            if prop == name { return Some(value) }
+        }
+        None
+    }
+
+    fn property_str(&self, name: &str) -> Option<&'a str>
+    {
+        c_str(self.property(name)?)
+    }
+
+    /* read a property of one cell, or of two if it's a 64-bit value that fits in 32 bits */
+    fn property_u32(&self, name: &str) -> Option<u32>
+    {
+        let value = self.property(name)?;
+        u32::try_from(read_cells(value, 0, (value.len() / 4).min(2) as u32)?).ok()
+    }
+
+    /* return true if path leads to this node. unit addresses can be left out of the path */
+    fn has_path(&self, path: &str) -> bool
+    {
+        let mut components = path.split('/').filter(|component| !component.is_empty());
+        for name in &self.path[1..self.depth]
+        {
+            match components.next()
+            {
+                Some(component) if component == *name => (),
+                Some(component) if !component.contains('@') && name.split('@').next() == Some(component) => (),
+                _ => return false
+            }
+        }
+        components.next().is_none()
+    }
+}
+
+/* every node in the tree, parents before their children */
+struct Nodes<'a>
+{
+    cursor: Cursor<'a>,
+    path: [&'a str; MAX_DEPTH],
+    cells: [(u32, u32); MAX_DEPTH], /* #address-cells and #size-cells of each open node */
+    depth: usize,
+    current: Option<Node<'a>>       /* the node whose properties are being passed over */
+}
+
+impl<'a> Iterator for Nodes<'a>
+{
+    type Item = Node<'a>;
+
+    fn next(&mut self) -> Option<Node<'a>>
+    {
+        loop
+        {
+            match self.cursor.next()?
+            {
+                Token::BeginNode(name) =>
+                {
+                    if self.depth == MAX_DEPTH { return None }
+                    let cells = if self.depth == 0 { DEFAULT_CELLS } else { self.cells[self.depth - 1] };
+                    self.path[self.depth] = name;
+                    self.cells[self.depth] = DEFAULT_CELLS;
+                    self.depth += 1;
+
+                    /* a child node comes after all its parent's properties */
+                    let node = Node { path: self.path, depth: self.depth, cells, props: self.cursor.clone() };
+                    if let Some(parent) = self.current.replace(node) { return Some(parent) }
+                },
+                Token::Prop(name, value) =>
+                {
+                    let cells = &mut self.cells[self.depth.checked_sub(1)?];
+                    match name
+                    {
+                        "#address-cells" => cells.0 = be32(value, 0)?,
+                        "#size-cells" => cells.1 = be32(value, 0)?,
+                        _ => ()
+                    }
+                },
+                Token::EndNode =>
+                {
+                    self.depth = self.depth.checked_sub(1)?;
+                    if let Some(node) = self.current.take() { return Some(node) }
+                },
+                Token::End => return None
+            }
+        }
+    }
+}
+
+/* read a big-endian 32-bit value at offset in bytes */
+fn be32(bytes: &[u8], offset: usize) -> Option<u32>
+{
+    let word = bytes.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_be_bytes([word[0], word[1], word[2], word[3]]))
+}
+
+/* read count cells, up to two, starting at the given cell */
+fn read_cells(bytes: &[u8], cell: usize, count: u32) -> Option<u64>
+{
+    match count
+    {
+        1 => be32(bytes, cell * 4).map(u64::from),
+        2 => Some((be32(bytes, cell * 4)? as u64) << 32 | be32(bytes, cell * 4 + 4)? as u64),
+        _ => None
+    }
+}
+
+/* return the nul-terminated string at the start of bytes */
+fn c_str(bytes: &[u8]) -> Option<&str>
+{
+    let end = bytes.iter().position(|byte| *byte == 0)?;
+    core::str::from_utf8(&bytes[..end]).ok()
+}
+
+fn align4(offset: usize) -> usize
+{
+    (offset + 3) & !3
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    /* build a DTB a node and a property at a time */
+    #[derive(Default)]
+    struct Dtb
+    {
+        structs: Vec<u8>,
+        strings: Vec<u8>
+    }
+
+    impl Dtb
+    {
+        fn token(&mut self, token: u32) -> &mut Self
+        {
+            self.structs.extend_from_slice(&token.to_be_bytes());
+            self
+        }
+
+        fn pad(&mut self)
+        {
+            self.structs.resize(align4(self.structs.len()), 0);
+        }
+
+        fn begin(&mut self, name: &str) -> &mut Self
+        {
+            self.token(FDT_BEGIN_NODE);
+            self.structs.extend_from_slice(name.as_bytes());
+            self.structs.push(0);
+            self.pad();
+            self
+        }
+
+        fn end(&mut self) -> &mut Self
+        {
+            self.token(FDT_END_NODE)
+        }
+
+        fn prop(&mut self, name: &str, value: &[u8]) -> &mut Self
+        {
+            let name_offset = self.strings.len() as u32;
+            self.strings.extend_from_slice(name.as_bytes());
+            self.strings.push(0);
+
+            self.token(FDT_PROP).token(value.len() as u32).token(name_offset);
+            self.structs.extend_from_slice(value);
+            self.pad();
+            self
+        }
+
+        fn cells(&mut self, name: &str, cells: &[u32]) -> &mut Self
+        {
+            let value: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes().to_vec()).collect();
+            self.prop(name, &value)
+        }
+
+        fn string(&mut self, name: &str, value: &str) -> &mut Self
+        {
+            self.prop(name, format!("{}\0", value).as_bytes())
+        }
+
+        fn build(&mut self) -> Vec<u8>
+        {
+            self.token(FDT_END);
+            let structs_offset = 40 + 16;   /* after the header and an empty memory reservation map */
+            let strings_offset = structs_offset + self.structs.len();
+            let total = strings_offset + self.strings.len();
+
+            let header = [FDT_MAGIC, total as u32, structs_offset as u32, strings_offset as u32, 40,
+                          FDT_VERSION, 16, 0, self.strings.len() as u32, self.structs.len() as u32];
+            let mut blob: Vec<u8> = header.iter().flat_map(|field| field.to_be_bytes().to_vec()).collect();
+            blob.extend_from_slice(&[0; 16]);
+            blob.extend_from_slice(&self.structs);
+            blob.extend_from_slice(&self.strings);
+            blob
+        }
+    }
+
+    /* a board with four usable serial ports, one of them without a clock-frequency, a disabled one, and something else */
+    fn board(stdout_path: &str) -> Vec<u8>
+    {
+        Dtb::default()
+            .begin("").cells("#address-cells", &[2]).cells("#size-cells", &[2])
+                .begin("chosen").string("stdout-path", stdout_path).end()
+                .begin("aliases").string("serial0", "/soc/serial@10000000").end()
+                .begin("soc").cells("#address-cells", &[2]).cells("#size-cells", &[2])
+                    .begin("serial@10000000").string("compatible", "ns16550a")
+                        .cells("reg", &[0, 0x1000_0000, 0, 0x100]).cells("clock-frequency", &[3_686_400])
+                        .cells("interrupts", &[10]).end()
+                    .begin("serial@10001000").prop("compatible", b"vendor,uart\0snps,dw-apb-uart\0")
+                        .cells("reg", &[0, 0x1000_1000, 0, 0x100]).cells("reg-shift", &[2]).cells("reg-io-width", &[4])
+                        .cells("clock-frequency", &[0, 1_843_200]).cells("current-speed", &[9600]).end()
+                    .begin("serial@10002000").string("compatible", "ns16550a").string("status", "disabled")
+                        .cells("reg", &[0, 0x1000_2000, 0, 0x100]).end()
+                    .begin("serial@10003000").string("compatible", "ns16550a")
+                        .cells("reg", &[0, 0x1000_3000, 0, 0x100]).cells("clock-frequency", &[3_686_400])
+                        .cells("interrupts", &[0, 33, 4]).end()
+                    .begin("serial@10004000").string("compatible", "snps,dw-apb-uart")
+                        .cells("reg", &[0, 0x1000_4000, 0, 0x100]).cells("clocks", &[1]).end()
+                    .begin("gpio@10060000").string("compatible", "sifive,gpio0")
+                        .cells("reg", &[0, 0x1006_0000, 0, 0x1000]).end()
+                .end()
+            .end()
+            .build()
+    }
+
+    #[test]
+    fn finds_uarts()
+    {
+        let dtb = board("serial0");
+        let tree = DeviceTree::new(&dtb).unwrap();
+        let uarts: Vec<FdtUart> = tree.uarts().collect();
+        assert_eq!(uarts.len(), 4);
+
+        assert_eq!((uarts[0].name(), uarts[0].base_addr(), uarts[0].size()), ("serial@10000000", 0x1000_0000, 0x100));
+        assert_eq!(uarts[0].interrupt_cells().collect::<Vec<u32>>(), [10]);
+        assert_eq!((uarts[0].clock_hz(), uarts[0].config().clock_hz()), (Some(3_686_400), 3_686_400));
+        assert_eq!(uarts[0].config().baud_rate(), DEFAULT_BAUD_RATE);
+
+        let config = uarts[1].config();
+        assert_eq!(uarts[1].base_addr(), 0x1000_1000);
+        assert_eq!(uarts[1].interrupt_cells().count(), 0);
+        assert_eq!((config.reg_shift(), config.access_width()), (2, AccessWidth::Bits32));
+        assert_eq!((config.clock_hz(), config.baud_rate()), (1_843_200, 9600));
+
+        /* a GIC's interrupt specifier, left for the caller to decode */
+        assert_eq!(uarts[2].interrupt_cells().collect::<Vec<u32>>(), [0, 33, 4]);
+
+        /* a clock given by phandle isn't followed, so the caller has to supply it */
+        assert_eq!((uarts[3].base_addr(), uarts[3].clock_hz()), (0x1000_4000, None));
+        assert_eq!(uarts[3].uart().err(), Some(Fault::InvalidConfig));
+    }
+
+    #[test]
+    fn chosen_console()
+    {
+        let dtb = board("serial0");
+        let console = DeviceTree::new(&dtb).unwrap().console().unwrap();
+        assert_eq!(console.base_addr(), 0x1000_0000);
+        assert_eq!(console.config().baud_rate(), DEFAULT_BAUD_RATE);
+
+        let dtb = board("/soc/serial@10001000:115200e7r");
+        let config = DeviceTree::new(&dtb).unwrap().console().unwrap().config();
+        assert_eq!((config.baud_rate(), config.parity(), config.data_bits()), (115200, Parity::Even, DataBits::Seven));
+        assert_eq!(config.flow_control(), FlowControl::RtsCts);
+
+        /* not a serial port this driver can handle */
+        let dtb = board("/soc/gpio:115200");
+        assert!(DeviceTree::new(&dtb).unwrap().console().is_none());
+
+        /* a serial port with no clock-frequency */
+        let dtb = board("/soc/serial@10004000:115200");
+        let console = DeviceTree::new(&dtb).unwrap().console().unwrap();
+        assert_eq!((console.clock_hz(), console.config().baud_rate()), (None, 115200));
+    }
+
+    #[test]
+    fn rejects_bad_blobs()
+    {
+        let dtb = board("serial0");
+        assert_eq!(DeviceTree::new(&dtb[..dtb.len() - 1]).err(), Some(Fault::BadDeviceTree));
+        assert_eq!(DeviceTree::new(&[0; 64]).err(), Some(Fault::BadDeviceTree));
+
+        /* a node left open */
+        let unterminated = Dtb::default().begin("").begin("soc").end().build();
+        assert_eq!(DeviceTree::new(&unterminated).err(), Some(Fault::BadDeviceTree));
+    }
+}
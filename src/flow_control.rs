@@ -0,0 +1,176 @@
+/* pause transmission when one end can't keep up, using the RTS and CTS lines or XON and XOFF
+ *
+ * With FlowControl::RtsCts the driver does the work: transmission is held while
+ * the other end deasserts CTS, and a BufferedUART deasserts RTS while its RX ring
+ * is nearly full. With FlowControl::AutoRtsCts, chips that can do this themselves
+ * are left to it, which reacts within a character rather than within an interrupt.
+ *
+ * FlowControl::XonXoff does the same in-band. Received XON and XOFF are only
+ * seen as received data is read, so while transmission is paused, keep reading,
+ * or use a BufferedUART, whose interrupt handler reads everything as it arrives.
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See README and LICENSE for usage and copying.
+ */
+
+use super::*;
+
+/* in-band flow control characters */
+pub(crate) const XON: u8 = 0x11;    /* DC1: resume transmission */
+pub(crate) const XOFF: u8 = 0x13;   /* DC3: pause transmission */
+
+impl<R: Registers> UART<R>
+{
+    /* return the flow control in use, which may have fallen back from AutoRtsCts or
+       AutoXonXoff to RtsCts or XonXoff if the chip couldn't do it by itself */
+    pub fn flow_control(&self) -> FlowControl
+    {
+        self.flow_control
+    }
+
+    /* return true unless the other end has asked us to pause, by deasserting CTS or sending XOFF */
+    pub(crate) fn clear_to_send(&self) -> bool
+    {
+        match self.flow_control
+        {
+            FlowControl::RtsCts => self.read_modem_status() & MODEM_STATUS_CTS != 0,
+            FlowControl::XonXoff => !self.tx_paused.load(Ordering::Relaxed),
+            _ => true
+        }
+    }
+
+    /* if byte is XON or XOFF and we're handling them, act on it and return true so it's
+       dropped from the received data */
+    pub(crate) fn handle_flow_control_char(&self, byte: u8) -> bool
+    {
+        if self.flow_control != FlowControl::XonXoff { return false }
+
+        match byte
+        {
+            XOFF => self.tx_paused.store(true, Ordering::Relaxed),
+            XON =>
+            {
+                self.tx_paused.store(false, Ordering::Relaxed);
+
+                /* a task may be waiting to send */
+                #[cfg(feature = "async")]
+                self.wakers.wake_tx();
+            },
+            _ => return false
+        }
+        true
+    }
+
+    /* ask the other end to pause, or to resume, sending to us by deasserting or asserting RTS,
+       if the driver is doing RTS/CTS flow control. a BufferedUART sends its own XON and XOFF
+       as it feeds the TX FIFO */
+    pub(crate) fn throttle_rx(&self, throttle: bool)
+    {
+        if self.flow_control == FlowControl::RtsCts { self.set_rts(!throttle) }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::cell::RefCell;
+    use model::Model;
+
+    /* record register writes to a chip that's always ready to transmit */
+    #[derive(Default)]
+    struct Recorder(RefCell<Vec<(usize, u8)>>);
+
+    impl Registers for Recorder
+    {
+        fn read(&self, reg: usize) -> u8
+        {
+            if reg == REG_LINE_STATUS { LINE_STATUS_THRE | LINE_STATUS_TEMT } else { 0 }
+        }
+
+        fn write(&self, reg: usize, val: u8) { self.0.borrow_mut().push((reg, val)) }
+    }
+
+    fn config(variant: Variant, flow_control: FlowControl) -> Config
+    {
+        Config::default().with_variant(variant).with_presence_check(false).with_flow_control(flow_control)
+    }
+
+    #[test]
+    fn held_while_cts_deasserted()
+    {
+        let model = Model::new();
+        let uart = UART::with_registers(&model, Config::default().with_flow_control(FlowControl::RtsCts)).unwrap();
+
+        assert_eq!(uart.send_byte(b'x'), Err(Fault::TxNotEmpty));
+        model.set_modem_inputs(true, false, false, false);
+        assert_eq!(uart.send_byte(b'x'), Ok(()));
+        assert_eq!(model.take_tx(), Some(b'x'));
+    }
+
+    #[test]
+    fn automatic_where_supported()
+    {
+        /* the model is a 16550A, which can't do it */
+        let model = Model::new();
+        let uart = UART::with_registers(&model, Config::default().with_flow_control(FlowControl::AutoRtsCts)).unwrap();
+        assert_eq!(uart.flow_control(), FlowControl::RtsCts);
+
+        let chip = Recorder::default();
+        let uart = UART::with_registers(&chip, config(Variant::Uart16750, FlowControl::AutoRtsCts)).unwrap();
+        assert_eq!(uart.flow_control(), FlowControl::AutoRtsCts);
+        assert!(chip.0.borrow().contains(&(REG_MODEM_CONTROL, 0b10_1011)));
+
+        let chip = Recorder::default();
+        let uart = UART::with_registers(&chip, config(Variant::Uart16950, FlowControl::AutoRtsCts)).unwrap();
+        assert_eq!(uart.flow_control(), FlowControl::AutoRtsCts);
+        assert_eq!(chip.0.borrow()[1..3], [(REG_LINE_CONTROL, LINE_CONTROL_CONFIG_B), (REG_EFR, 0xd0)]);
+
+        /* it needs the FIFOs */
+        let chip = Recorder::default();
+        let uart = UART::with_registers(&chip, config(Variant::Uart16950, FlowControl::AutoRtsCts).without_fifo()).unwrap();
+        assert_eq!(uart.flow_control(), FlowControl::RtsCts);
+    }
+
+    #[test]
+    fn xon_xoff_in_band()
+    {
+        let model = Model::new();
+        let uart = UART::with_registers(&model, Config::default().with_flow_control(FlowControl::XonXoff)).unwrap();
+
+        /* the control characters are taken out of the data */
+        model.receive(XOFF);
+        model.receive(b'a');
+        assert_eq!(uart.read_byte(), Ok(b'a'));
+        assert_eq!(uart.send_byte(b'x'), Err(Fault::TxNotEmpty));
+
+        model.receive(XON);
+        assert_eq!(uart.read_byte(), Err(Fault::DataNotReady));
+        assert_eq!(uart.send_byte(b'x'), Ok(()));
+        assert_eq!(model.take_tx(), Some(b'x'));
+
+        /* and left alone without flow control */
+        let uart = UART::with_registers(&model, Config::default()).unwrap();
+        model.receive(XOFF);
+        assert_eq!(uart.read_byte(), Ok(XOFF));
+    }
+
+    #[test]
+    fn xon_xoff_in_chip()
+    {
+        let model = Model::new();
+        let uart = UART::with_registers(&model, Config::default().with_flow_control(FlowControl::AutoXonXoff)).unwrap();
+        assert_eq!(uart.flow_control(), FlowControl::XonXoff);
+
+        let chip = Recorder::default();
+        let uart = UART::with_registers(&chip, config(Variant::Uart16650, FlowControl::AutoXonXoff)).unwrap();
+        assert_eq!(uart.flow_control(), FlowControl::AutoXonXoff);
+        assert_eq!(chip.0.borrow()[1..5], [
+            (REG_LINE_CONTROL, LINE_CONTROL_CONFIG_B),
+            (REG_XON1, XON),
+            (REG_XOFF1, XOFF),
+            (REG_EFR, 0x1a)
+        ]);
+    }
+}
@@ -0,0 +1,237 @@
+/* implement the embedded-hal-nb and embedded-io serial traits so
+ * drivers written against them can use a UART directly
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See README and LICENSE for usage and copying.
+ */
+
+use super::*;
+
+#[cfg(feature = "embedded-hal-nb")]
+mod nb_serial
+{
+    use super::*;
+    use embedded_hal_nb::{nb, serial};
+
+    impl serial::Error for Fault
+    {
+        fn kind(&self) -> serial::ErrorKind
+        {
+            match self
+            {
+                Fault::Overrun => serial::ErrorKind::Overrun,
+                Fault::Parity(_) => serial::ErrorKind::Parity,
+                Fault::Framing(_) => serial::ErrorKind::FrameFormat,
+                _ => serial::ErrorKind::Other
+            }
+        }
+    }
+
+    impl<R: Registers> serial::ErrorType for UART<R>
+    {
+        type Error = Fault;
+    }
+
+    /* these never wait: if the chip isn't ready they return WouldBlock */
+    impl<R: Registers> serial::Read<u8> for UART<R>
+    {
+        fn read(&mut self) -> nb::Result<u8, Fault>
+        {
+            match self.read_byte_timeout(Timeout::NonBlocking)
+            {
+                Ok(byte) => Ok(byte),
+                Err(Fault::DataNotReady) => Err(nb::Error::WouldBlock),
+                Err(e) => Err(nb::Error::Other(e))
+            }
+        }
+    }
+
+    impl<R: Registers> serial::Write<u8> for UART<R>
+    {
+        fn write(&mut self, word: u8) -> nb::Result<(), Fault>
+        {
+            match self.send_byte_timeout(word, Timeout::NonBlocking)
+            {
+                Ok(()) => Ok(()),
+                Err(Fault::TxNotEmpty) => Err(nb::Error::WouldBlock),
+                Err(e) => Err(nb::Error::Other(e))
+            }
+        }
+
+        fn flush(&mut self) -> nb::Result<(), Fault>
+        {
+            match self.is_transmitter_idle()
+            {
+                true => Ok(()),
+                false => Err(nb::Error::WouldBlock)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "embedded-io")]
+mod io
+{
+    use super::*;
+
+    impl embedded_io::Error for Fault
+    {
+        fn kind(&self) -> embedded_io::ErrorKind
+        {
+            match self
+            {
+                Fault::TxNotEmpty | Fault::DataNotReady => embedded_io::ErrorKind::TimedOut,
+                Fault::Parity(_) | Fault::Framing(_) | Fault::BadDeviceTree => embedded_io::ErrorKind::InvalidData,
+                Fault::SizeTooSmall | Fault::InvalidConfig | Fault::BaudRateUnachievable => embedded_io::ErrorKind::InvalidInput,
+                Fault::NoDevice => embedded_io::ErrorKind::NotFound,
+                _ => embedded_io::ErrorKind::Other
+            }
+        }
+    }
+
+    impl<R: Registers> embedded_io::ErrorType for UART<R>
+    {
+        type Error = Fault;
+    }
+
+    /* a read blocks until at least one byte has arrived, as embedded-io requires, whatever
+       the UART's RX timeout: check ReadReady first to avoid blocking. a write waits for room
+       in the TX FIFO according to the UART's TX timeout. both then move as many more bytes as
+       they can without waiting. a byte with a line error ends a read early, and is reported
+       as an error by the next read */
+    impl<R: Registers> embedded_io::Read for UART<R>
+    {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Fault>
+        {
+            if buf.is_empty() { return Ok(0) }
+
+            loop
+            {
+                if let Some(result) = self.read_available(buf) { return result }
+            }
+        }
+    }
+
+    impl<R: Registers> embedded_io::Write for UART<R>
+    {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Fault>
+        {
+            self.send(buf)
+        }
+
+        /* wait until the last byte has left the transmitter */
+        fn flush(&mut self) -> Result<(), Fault>
+        {
+            UART::flush(self)
+        }
+    }
+
+    impl<R: Registers> embedded_io::ReadReady for UART<R>
+    {
+        fn read_ready(&mut self) -> Result<bool, Fault>
+        {
+            Ok(self.overrun_pending.load(Ordering::Relaxed) || self.is_data_ready())
+        }
+    }
+
+    impl<R: Registers> embedded_io::WriteReady for UART<R>
+    {
+        fn write_ready(&mut self) -> Result<bool, Fault>
+        {
+            Ok(self.ready_to_send())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use model::{Model, RxError};
+
+    #[cfg(feature = "embedded-hal-nb")]
+    #[test]
+    fn nb_serial()
+    {
+        use embedded_hal_nb::{nb, serial::{Read, Write}};
+
+        let model = Model::new();
+        let mut uart = UART::with_registers(&model, Config::default()).unwrap();
+
+        assert_eq!(Read::read(&mut uart), Err(nb::Error::WouldBlock));
+        model.receive(b'a');
+        model.receive_with_error(b'b', RxError::Framing);
+        assert_eq!(Read::read(&mut uart), Ok(b'a'));
+        assert_eq!(Read::read(&mut uart), Err(nb::Error::Other(Fault::Framing(b'b'))));
+
+        model.set_tx_stalled(true);
+        assert_eq!(Write::write(&mut uart, b'c'), Ok(()));
+        assert_eq!(Write::write(&mut uart, b'd'), Err(nb::Error::WouldBlock));
+        assert_eq!(Write::flush(&mut uart), Err(nb::Error::WouldBlock));
+        model.set_tx_stalled(false);
+        assert_eq!(Write::flush(&mut uart), Ok(()));
+        assert_eq!(model.take_tx(), Some(b'c'));
+    }
+
+    /* a model where a byte arrives after the line status has been polled a number of times */
+    #[cfg(feature = "embedded-io")]
+    struct Late
+    {
+        model: Model,
+        polls: core::cell::Cell<usize>
+    }
+
+    #[cfg(feature = "embedded-io")]
+    impl Registers for Late
+    {
+        fn read(&self, reg: usize) -> u8
+        {
+            if reg == REG_LINE_STATUS && self.polls.get() > 0
+            {
+                self.polls.set(self.polls.get() - 1);
+                if self.polls.get() == 0 { self.model.receive(b'z') }
+            }
+            self.model.read(reg)
+        }
+
+        fn write(&self, reg: usize, val: u8) { self.model.write(reg, val) }
+    }
+
+    #[cfg(feature = "embedded-io")]
+    #[test]
+    fn io_read_write()
+    {
+        use embedded_io::{Read, Write, ReadReady, WriteReady};
+
+        let model = Model::new();
+        let mut uart = UART::with_registers(&model, Config::default()).unwrap();
+        assert_eq!(uart.read_ready(), Ok(false));
+
+        /* a read waits for the first byte, even without an RX timeout */
+        let late = Late { model: Model::new(), polls: Default::default() };
+        let mut waiting = UART::with_registers(&late, Config::default()).unwrap();
+        late.polls.set(10);
+        let mut buf = [0u8; 4];
+        assert_eq!(Read::read(&mut waiting, &mut buf), Ok(1));
+        assert_eq!((buf[0], late.polls.get()), (b'z', 0));
+
+        for byte in b"abc" { model.receive(*byte) }
+        model.receive_with_error(b'x', RxError::Parity);
+        model.receive(b'd');
+        assert_eq!(uart.read_ready(), Ok(true));
+
+        /* the read stops short of the byte with the parity error */
+        let mut buf = [0u8; 8];
+        assert_eq!(Read::read(&mut uart, &mut buf), Ok(3));
+        assert_eq!(&buf[..3], b"abc");
+        assert_eq!(Read::read(&mut uart, &mut buf), Err(Fault::Parity(b'x')));
+        assert_eq!(Read::read(&mut uart, &mut buf), Ok(1));
+
+        assert_eq!(uart.write_ready(), Ok(true));
+        Write::write_all(&mut uart, b"hello").unwrap();
+        Write::flush(&mut uart).unwrap();
+        let sent: Vec<u8> = core::iter::from_fn(|| model.take_tx()).collect();
+        assert_eq!(sent, b"hello");
+    }
+}
@@ -0,0 +1,231 @@
+/* enable, identify and service the 16550's interrupts
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See README and LICENSE for usage and copying.
+ */
+
+use super::*;
+
+/* interrupt sources the chip can raise */
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct InterruptEnable
+{
+    pub rx_data: bool,      /* RX data reached the FIFO trigger level, or timed out below it */
+    pub tx_empty: bool,     /* transmitter holding register is empty */
+    pub line_status: bool,  /* overrun, parity, framing error or break received */
+    pub modem_status: bool  /* CTS, DSR, RI or DCD changed */
+}
+
+impl InterruptEnable
+{
+    /* convert to and from the interrupt enable register's bit layout */
+    pub fn bits(&self) -> u8
+    {
+        let mut bits = 0;
+        if self.rx_data { bits |= IRQ_EN_RX_DATA }
+        if self.tx_empty { bits |= IRQ_EN_TX_EMPTY }
+        if self.line_status { bits |= IRQ_EN_LINE_STATUS }
+        if self.modem_status { bits |= IRQ_EN_MODEM_STATUS }
+        bits
+    }
+
+    pub fn from_bits(bits: u8) -> Self
+    {
+        InterruptEnable
+        {
+            rx_data: bits & IRQ_EN_RX_DATA != 0,
+            tx_empty: bits & IRQ_EN_TX_EMPTY != 0,
+            line_status: bits & IRQ_EN_LINE_STATUS != 0,
+            modem_status: bits & IRQ_EN_MODEM_STATUS != 0
+        }
+    }
+}
+
+/* interrupt causes found and serviced by handle_interrupt() */
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Events
+{
+    pub line_status: bool,                /* line errors were recorded; see line_error_counts() and read_byte() */
+    pub break_received: bool,             /* a break is next in the RX FIFO, and read_byte() will return Fault::Break.
+                                             this stays set until the break is read, so doesn't count towards any() */
+    pub rx_data: bool,                    /* RX data is waiting at or above the FIFO trigger level */
+    pub rx_timeout: bool,                 /* RX data is waiting below the trigger level and nothing else has arrived */
+    pub tx_empty: bool,                   /* there's room to transmit more data */
+    pub modem_status: Option<ModemStatus> /* a modem input line changed, and the status read as a result */
+}
+
+impl Events
+{
+    /* return true if this UART raised an interrupt */
+    pub fn any(&self) -> bool
+    {
+        self.line_status || self.rx_data || self.rx_timeout || self.tx_empty
+            || self.modem_status.is_some()
+    }
+
+    /* return true if there are received bytes to read */
+    pub fn rx_ready(&self) -> bool
+    {
+        self.rx_data || self.rx_timeout
+    }
+}
+
+impl<R: Registers> UART<R>
+{
+    /* select which interrupts the chip raises */
+    pub fn set_interrupt_enable(&self, enable: InterruptEnable)
+    {
+        self.irq_enable.store(enable.bits(), Ordering::Relaxed);
+        self.write_irq_enable();
+    }
+
+    pub fn interrupt_enable(&self) -> InterruptEnable
+    {
+        InterruptEnable::from_bits(self.irq_enable.load(Ordering::Relaxed))
+    }
+
+    /* program the chip with the interrupts wanted. an interrupt handler may change
+       them while this is going on, so keep going until the chip has the latest */
+    pub(crate) fn write_irq_enable(&self)
+    {
+        loop
+        {
+            let bits = self.irq_enable.load(Ordering::Relaxed);
+            self.write_reg(REG_IRQ_EN, bits);
+            if self.irq_enable.load(Ordering::Relaxed) == bits { return }
+        }
+    }
+
+    /* call this from the UART's IRQ handler. it works through the chip's pending
+       interrupts in priority order, acknowledging line status, THR empty and modem
+       status interrupts, and returns which causes it found. it stops at an RX
+       data or character timeout interrupt as those are only acknowledged by reading
+       the RX FIFO, which is left to the caller, eg by calling read_byte() until it
+       returns DataNotReady. Events::any() is false if the interrupt wasn't ours */
+    pub fn handle_interrupt(&self) -> Events
+    {
+        let events = self.service_interrupt();
+
+        #[cfg(feature = "async")]
+        self.wake_tasks(&events);
+
+        events
+    }
+
+    /* identify and acknowledge pending interrupts as handle_interrupt() does, without
+       waking tasks, for a BufferedUART, which reads and writes the FIFOs itself */
+    pub(crate) fn service_interrupt(&self) -> Events
+    {
+        let mut events = Events::default();
+
+        for _ in 0..IRQ_PASSES_MAX
+        {
+            /* reading IIR acknowledges a THR empty interrupt */
+            match self.read_reg(REG_IRQ_ID) & IRQ_ID_MASK
+            {
+                IRQ_ID_NONE => break,
+                IRQ_ID_LINE_STATUS =>
+                {
+                    /* reading LSR acknowledges the interrupt, and records the errors */
+                    self.read_line_status();
+                    events.line_status = true;
+                },
+                IRQ_ID_RX_DATA =>
+                {
+                    events.rx_data = true;
+                    break;
+                },
+                IRQ_ID_RX_TIMEOUT =>
+                {
+                    events.rx_timeout = true;
+                    break;
+                },
+                IRQ_ID_TX_EMPTY => events.tx_empty = true,
+                /* reading MSR acknowledges the interrupt */
+                IRQ_ID_MODEM_STATUS => events.modem_status = Some(self.modem_status()),
+
+                /* not a cause we recognize, so don't keep reading it */
+                _ => break
+            }
+        }
+
+        /* the break's error bits may have been picked up by an earlier LSR read, and modem
+        status changes by an earlier MSR read, which also acknowledged their interrupt */
+        events.break_received = self.break_pending();
+        if events.modem_status.is_none()
+            && self.irq_enable.load(Ordering::Relaxed) & IRQ_EN_MODEM_STATUS != 0
+            && self.modem_changes_pending.load(Ordering::Relaxed) != 0
+        {
+            events.modem_status = Some(self.modem_status());
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use model::{Model, RxError};
+
+    fn all() -> InterruptEnable
+    {
+        InterruptEnable { rx_data: true, tx_empty: true, line_status: true, modem_status: true }
+    }
+
+    #[test]
+    fn nothing_pending()
+    {
+        let model = Model::new();
+        let uart = UART::with_registers(&model, Config::default()).unwrap();
+        assert!(!uart.handle_interrupt().any());
+    }
+
+    #[test]
+    fn configured_interrupts_enabled()
+    {
+        let model = Model::new();
+        let uart = UART::with_registers(&model, Config::default().with_interrupts(all())).unwrap();
+        assert_eq!(uart.interrupt_enable(), all());
+
+        uart.set_interrupt_enable(InterruptEnable::default());
+        assert_eq!(model.interrupt_enable(), 0);
+    }
+
+    #[test]
+    fn service_in_priority_order()
+    {
+        let model = Model::new();
+        let config = Config::default().with_interrupts(all()).with_fifo_trigger(FifoTrigger::Bytes1);
+        let uart = UART::with_registers(&model, config).unwrap();
+
+        /* THR empty and a modem status change are acknowledged and reported together */
+        model.set_modem_inputs(true, true, false, true);
+        let status = ModemStatus::from_bits(0b1011_1011);
+        assert_eq!(uart.handle_interrupt(), Events { tx_empty: true, modem_status: Some(status), ..Events::default() });
+        assert!(!model.interrupt_pending());
+
+        /* a parity error is recorded, then the handler stops at the RX data for the caller */
+        model.receive_with_error(b'e', RxError::Parity);
+        assert_eq!(uart.handle_interrupt(), Events { line_status: true, rx_data: true, ..Events::default() });
+        assert_eq!(uart.line_error_counts().parity, 1);
+        assert_eq!(uart.read_byte(), Err(Fault::Parity(b'e')));
+        assert!(!model.interrupt_pending());
+    }
+
+    #[test]
+    fn character_timeout()
+    {
+        let model = Model::new();
+        let uart = UART::with_registers(&model, Config::default()).unwrap();
+
+        model.receive(b'a');
+        assert!(!uart.handle_interrupt().any());
+        model.character_timeout();
+        let events = uart.handle_interrupt();
+        assert!(events.rx_timeout && events.rx_ready());
+        assert_eq!(uart.read_byte(), Ok(b'a'));
+        assert!(!uart.handle_interrupt().any());
+    }
+}
@@ -11,148 +11,659 @@
 #![cfg_attr(not(test), no_std)]
 #![allow(dead_code)]
 
-use core::ptr::{write_volatile, read_volatile};
+mod config;
+mod divisor;
+mod registers;
+mod timeout;
+mod line_status;
+mod interrupts;
+mod buffered;
+mod console;
+mod variant;
+mod presence;
+mod self_test;
+mod flow_control;
+mod modem;
+mod break_condition;
+mod transmit;
+mod receive;
+mod state;
+mod mux;
 
-const REG_TOTAL_SIZE: usize = 8;        /* 8 byte-size registers */
+#[cfg(any(feature = "embedded-hal-nb", feature = "embedded-io"))]
+mod hal;
+
+#[cfg(feature = "async")]
+mod async_io;
+
+#[cfg(any(test, feature = "model", feature = "virt"))]
+mod device;
+#[cfg(any(test, feature = "model"))]
+pub mod model;
+#[cfg(any(test, feature = "virt"))]
+pub mod virt;
+#[cfg(any(test, feature = "fdt"))]
+pub mod fdt;
+pub use config::{Config, AccessWidth, DataBits, Parity, StopBits, FifoTrigger, ModemControl, FlowControl};
+pub use config::{DEFAULT_BAUD_RATE, DEFAULT_CLOCK_HZ, MAX_REG_SHIFT};
+pub use divisor::{Divisor, DEFAULT_BAUD_TOLERANCE_BP};
+pub use registers::{Registers, Mmio};
+pub use timeout::{Timeout, TimeSource};
+pub use line_status::LineErrorCounts;
+pub use interrupts::{InterruptEnable, Events};
+pub use buffered::{RingBuffer, BufferedUART};
+pub use console::{Console, set_console, print_to_console};
+pub use variant::Variant;
+pub use presence::check_present;
+pub use self_test::{SelfTestFailure, LoopbackSignal};
+pub use modem::ModemStatus;
+pub use state::SavedState;
+pub use mux::{ConsoleMux, Channel, Focus, MUX_ESCAPE};
+
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use line_status::LineErrorCounters;
+use config::{FIFO_CONTROL_ENABLE, FIFO_CONTROL_RX_RESET, FIFO_CONTROL_TX_RESET, FIFO_CONTROL_64BYTE};
+use config::{MODEM_CONTROL_RTS, MODEM_CONTROL_AFE};
+use flow_control::{XON, XOFF};
+
+const REG_COUNT: usize = 8;             /* 8 byte-size registers, spaced out by reg_shift */
 
 /* registers 0-7 in the 16550 */
 const REG_DATA: usize = 0;              /* byte to transmit or receive */
 const REG_DIVISOR_LSB: usize = 0;       /* least sig byte of divisor in DLAB mode */
 const REG_DIVISOR_MSB: usize = 1;       /* most sig byte of divisor in DLAB mode */
 const REG_IRQ_EN: usize = 1;            /* interrupt enable */
-const REG_FIFO_CONTROL: usize = 2;      /* FIFO and IRQ id control */
+const REG_FIFO_CONTROL: usize = 2;      /* FIFO and IRQ id control (write only) */
+const REG_IRQ_ID: usize = 2;            /* interrupt identification (read only) */
 const REG_LINE_CONTROL: usize = 3;      /* communications control bits */
 const REG_MODEM_CONTROL: usize = 4;     /* modem control bits */
 const REG_LINE_STATUS: usize = 5;       /* communications status bits */
+const REG_MODEM_STATUS: usize = 6;      /* modem input lines and their changes */
+const REG_SCRATCH: usize = 7;           /* spare byte for software's use */
+const REG_EFR: usize = 2;               /* enhanced features, while LCR is LINE_CONTROL_CONFIG_B (16650 and later) */
+const REG_XON1: usize = 4;              /* XON character, while LCR is LINE_CONTROL_CONFIG_B */
+const REG_XOFF1: usize = 6;             /* XOFF character, while LCR is LINE_CONTROL_CONFIG_B */
+
+/* define interrupt enable bits */
+const IRQ_EN_RX_DATA: u8 = 1 << 0;      /* RX data available or character timeout */
+const IRQ_EN_TX_EMPTY: u8 = 1 << 1;     /* transmitter holding register empty */
+const IRQ_EN_LINE_STATUS: u8 = 1 << 2;  /* receiver line status error or break */
+const IRQ_EN_MODEM_STATUS: u8 = 1 << 3; /* change in modem input lines */
+
+/* define interrupt identification values, in priority order */
+const IRQ_ID_MASK: u8 = 0x0f;           /* low four bits identify the pending interrupt */
+const IRQ_ID_NONE: u8 = 0b0001;         /* no interrupt pending */
+const IRQ_ID_LINE_STATUS: u8 = 0b0110;  /* receiver line status */
+const IRQ_ID_RX_DATA: u8 = 0b0100;      /* RX data reached the trigger level */
+const IRQ_ID_RX_TIMEOUT: u8 = 0b1100;   /* RX data waiting below the trigger level */
+const IRQ_ID_TX_EMPTY: u8 = 0b0010;     /* transmitter holding register empty */
+const IRQ_ID_MODEM_STATUS: u8 = 0b0000; /* modem status change */
+const IRQ_ID_FIFOS_ENABLED: u8 = 0b11 << 6; /* FIFOs are enabled and working */
+const IRQ_ID_64BYTE_FIFO: u8 = 1 << 5;  /* 16750 only: FIFOs are in 64-byte mode */
 
 /* define line control bits */
+const LINE_CONTROL_BREAK: u8 = 1 << 6;  /* hold the TX line low */
 const LINE_CONTROL_DLAB: u8 = 1 << 7;   /* enable divisor latch access bit (DLAB) */
+const LINE_CONTROL_CONFIG_B: u8 = 0xbf; /* magic value that exposes the EFR */
+
+/* define enhanced feature bits */
+const EFR_RX_XON1_XOFF1: u8 = 0b10;     /* act on and discard received XON1 and XOFF1 */
+const EFR_TX_XON1_XOFF1: u8 = 0b1000;   /* send XON1 and XOFF1 as the RX FIFO fills and empties */
+const EFR_ENHANCED: u8 = 1 << 4;        /* unlock the enhanced functions, including deeper FIFOs */
+const EFR_AUTO_RTS: u8 = 1 << 6;        /* deassert RTS while the RX FIFO is above its trigger level */
+const EFR_AUTO_CTS: u8 = 1 << 7;        /* hold transmission while CTS is deasserted */
 
 /* define line status bits */
 const LINE_STATUS_DR: u8 = 1 << 0;      /* data ready */
+const LINE_STATUS_OE: u8 = 1 << 1;      /* overrun error: a received byte was lost */
+const LINE_STATUS_PE: u8 = 1 << 2;      /* parity error in the byte at the top of the RX FIFO */
+const LINE_STATUS_FE: u8 = 1 << 3;      /* framing error in the byte at the top of the RX FIFO */
+const LINE_STATUS_BI: u8 = 1 << 4;      /* break interrupt: RX line held low for a whole character */
 const LINE_STATUS_THRE: u8 = 1 << 5;    /* transmitter holding register empty */
+const LINE_STATUS_TEMT: u8 = 1 << 6;    /* transmitter completely empty */
+const LINE_STATUS_RXFE: u8 = 1 << 7;    /* at least one error in the RX FIFO */
+const LINE_STATUS_BYTE_ERRORS: u8 = LINE_STATUS_PE | LINE_STATUS_FE | LINE_STATUS_BI;
+
+/* define modem status bits */
+const MODEM_STATUS_DCTS: u8 = 1 << 0;   /* CTS changed since last read */
+const MODEM_STATUS_DDSR: u8 = 1 << 1;   /* DSR changed since last read */
+const MODEM_STATUS_TERI: u8 = 1 << 2;   /* RI went inactive since last read */
+const MODEM_STATUS_DDCD: u8 = 1 << 3;   /* DCD changed since last read */
+const MODEM_STATUS_CTS: u8 = 1 << 4;    /* clear to send */
+const MODEM_STATUS_DSR: u8 = 1 << 5;    /* data set ready */
+const MODEM_STATUS_RI: u8 = 1 << 6;     /* ring indicator */
+const MODEM_STATUS_DCD: u8 = 1 << 7;    /* data carrier detect */
+const MODEM_STATUS_CHANGES: u8 = MODEM_STATUS_DCTS | MODEM_STATUS_DDSR | MODEM_STATUS_TERI | MODEM_STATUS_DDCD;
 
-/* to avoid infinite loops, give up checking
-   for a byte to arrive or for a byte to be
-   transmitted after this many check iterations */
+/* to avoid infinite loops, by default give up
+   checking for a byte to be transmitted after
+   this many check iterations */
 const LOOP_MAX: usize = 1000;
 
+/* give up servicing interrupts after this many passes through the
+   interrupt identification register so a stuck chip can't wedge an IRQ handler */
+const IRQ_PASSES_MAX: usize = 8;
+
+/* bytes held by the 16550A's TX and RX FIFOs */
+const FIFO_DEPTH: usize = 16;
+
 /* possible error conditions supported at this time */
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Fault
 {
-    SizeTooSmall,    /* the size of the MMIO area is unexpectedly small */
-    TxNotEmpty,     /* gave up waiting to transmit */
-    DataNotReady    /* gave up waiting to send */
+    SizeTooSmall,           /* the size of the MMIO area is unexpectedly small */
+    TxNotEmpty,             /* gave up waiting to transmit */
+    DataNotReady,           /* gave up waiting to send */
+    InvalidConfig,          /* the requested line settings can't be programmed into the chip */
+    BaudRateUnachievable,   /* no divisor gets close enough to the requested baud rate */
+    Overrun,                /* received bytes were lost because they weren't read in time */
+    Parity(u8),             /* this byte was received with a bad parity bit */
+    Framing(u8),            /* this byte was received without a valid stop bit */
+    Break,                  /* the RX line was held low for longer than a character */
+    ConsoleAlreadySet,      /* a global console has already been registered */
+    NoDevice,               /* nothing that behaves like a 16550 responded at this address */
+    SelfTest(SelfTestFailure), /* the chip failed its loopback self-test */
+    BadDeviceTree           /* a devicetree blob is malformed or of an unsupported version */
 }
 
+/* a 16550 driven through registers of type R, by default mapped into memory */
 #[derive(Debug)]
-pub struct UART
+pub struct UART<R: Registers = Mmio>
 {
-    base_addr: usize
+    regs: R,
+    variant: Variant,
+    divisor: Divisor,
+    tx_timeout: Timeout,
+    rx_timeout: Timeout,
+    lf_to_crlf: bool,               /* send CR before LF when sending strings */
+    flow_control: FlowControl,      /* as set up, after falling back from automatic flow control if need be */
+    tx_paused: AtomicBool,          /* XOFF has been received, and XON not yet */
+    fifo_control: u8,               /* FCR is write-only so keep a copy, minus the reset bits */
+    irq_enable: AtomicU8,           /* the interrupts wanted, which thread and interrupt context can both change */
+    line_errors: LineErrorCounters,
+    overrun_pending: AtomicBool,    /* an overrun was seen but not yet reported */
+    rx_errors_pending: AtomicU8,    /* error bits seen for the byte at the top of the RX FIFO */
+    modem_changes_pending: AtomicU8, /* modem status change bits seen but not yet reported */
+    shutdown_on_drop: bool,
+    #[cfg(feature = "async")]
+    wakers: async_io::Wakers        /* tasks waiting for RX data or room to transmit */
 }
 
-impl UART
+impl UART<Mmio>
 {
-    /* create and initialize a standard 8-n-1 UART object, or fail with a reason code.
-    TODO: Configure this initialization */
+    /* create and initialize a standard 38400 8-n-1 UART object, or fail with a reason code */
     pub fn new(base_addr: usize, size: usize) -> Result<Self, Fault>
+    {
+        UART::with_config(base_addr, size, Config::default())
+    }
+
+    /* create and initialize a UART object using the given line settings, or fail with a reason code */
+    pub fn with_config(base_addr: usize, size: usize, config: Config) -> Result<Self, Fault>
     {
         /* give up if the available MMIO area is smaller than the register area we need */
-        if REG_TOTAL_SIZE > size { return Err(Fault::SizeTooSmall) }
+        if config.register_span(REG_COUNT)? > size { return Err(Fault::SizeTooSmall) }
 
-        let uart = UART { base_addr };
+        UART::with_registers(Mmio::new(base_addr, config.reg_shift(), config.access_width()), config)
+    }
+
+    /* return size of this controller's MMIO space in bytes */
+    pub fn size(&self) -> usize
+    {
+        self.regs.size(REG_COUNT)
+    }
+}
+
+impl<R: Registers> UART<R>
+{
+    /* create and initialize a UART object that accesses the chip through regs, or fail with a reason code */
+    pub fn with_registers(regs: R, config: Config) -> Result<Self, Fault>
+    {
+        /* check the settings before touching the hardware */
+        let line_control = config.line_control()?;
+        let divisor = config.divisor()?;
+        config.register_span(REG_COUNT)?;
+
+        if config.presence_check() { check_present(&regs)? }
+
+        let variant = match config.variant()
+        {
+            Some(variant) => variant,
+            None => Variant::probe(&regs)
+        };
+
+        /* leave the FIFOs off if they're missing or broken, and use all of a 16750's */
+        let mut fifo_control = if variant.has_fifo() { config.fifo_control() } else { 0 };
+        if variant == Variant::Uart16750 && fifo_control != 0 { fifo_control |= FIFO_CONTROL_64BYTE }
+
+        /* automatic flow control works from the RX FIFO level, so it needs the FIFOs on */
+        let flow_control = match config.flow_control()
+        {
+            FlowControl::AutoRtsCts if !variant.has_auto_flow_control() || fifo_control == 0 => FlowControl::RtsCts,
+            FlowControl::AutoXonXoff if !variant.has_efr() || fifo_control == 0 => FlowControl::XonXoff,
+            flow_control => flow_control
+        };
+
+        let uart = UART
+        {
+            regs,
+            variant,
+            divisor,
+            tx_timeout: config.tx_timeout(),
+            rx_timeout: config.rx_timeout(),
+            lf_to_crlf: config.lf_to_crlf(),
+            flow_control,
+            tx_paused: AtomicBool::new(false),
+            fifo_control: fifo_control & !(FIFO_CONTROL_RX_RESET | FIFO_CONTROL_TX_RESET),
+            irq_enable: AtomicU8::new(0),
+            line_errors: LineErrorCounters::default(),
+            overrun_pending: AtomicBool::new(false),
+            rx_errors_pending: AtomicU8::new(0),
+            modem_changes_pending: AtomicU8::new(0),
+            shutdown_on_drop: config.shutdown_on_drop(),
+            #[cfg(feature = "async")]
+            wakers: async_io::Wakers::default()
+        };
 
         /* disable IRQs from this chip */
         uart.write_reg(REG_IRQ_EN, 0);
 
-        /* enable DLAB, set speed to 38400 bps, disable DLAB,
-        and set data 8 bits in length, no parity, one stop bit */
+        /* chips with an EFR only use their deeper FIFOs in enhanced mode,
+        which is also where their automatic flow control is enabled */
+        if variant.has_efr()
+        {
+            let mut efr = EFR_ENHANCED;
+            uart.write_reg(REG_LINE_CONTROL, LINE_CONTROL_CONFIG_B);
+            match flow_control
+            {
+                FlowControl::AutoRtsCts => efr |= EFR_AUTO_RTS | EFR_AUTO_CTS,
+                FlowControl::AutoXonXoff =>
+                {
+                    uart.write_reg(REG_XON1, XON);
+                    uart.write_reg(REG_XOFF1, XOFF);
+                    efr |= EFR_RX_XON1_XOFF1 | EFR_TX_XON1_XOFF1;
+                },
+                _ => ()
+            }
+            uart.write_reg(REG_EFR, efr);
+        }
+
+        /* enable DLAB, program the baud rate divisor, disable DLAB,
+        and set the data length, parity and stop bits */
         uart.write_reg(REG_LINE_CONTROL, LINE_CONTROL_DLAB);
-        uart.write_reg(REG_DIVISOR_LSB, 3); // 115200 / 3 = 38400 bps
-        uart.write_reg(REG_DIVISOR_MSB, 0);
-        uart.write_reg(REG_LINE_CONTROL, 0b0011); // len = 8, 1 stop bit, no parity, dlab = 0
+        uart.write_reg(REG_DIVISOR_LSB, (divisor.value() & 0xff) as u8);
+        uart.write_reg(REG_DIVISOR_MSB, (divisor.value() >> 8) as u8);
+
+        /* the 16750's 64-byte FIFO mode can only be selected while DLAB is set */
+        if variant == Variant::Uart16750 { uart.write_reg(REG_FIFO_CONTROL, fifo_control) }
+        uart.write_reg(REG_LINE_CONTROL, line_control);
 
-        /* enable FIFO, set IRQ watermark to 14 bytes */
-        uart.write_reg(REG_FIFO_CONTROL, 0xc7);
+        /* enable and reset the FIFOs and set the IRQ watermark, or run without FIFOs */
+        uart.write_reg(REG_FIFO_CONTROL, fifo_control);
 
-        /* enable IRQ line 1, clear RTS and DTR */
-        uart.write_reg(REG_MODEM_CONTROL, 0b1011);
+        /* drive the modem control lines, including OUT2 which gates the IRQ line on many boards.
+        the 16750 only drives RTS automatically if it's set along with AFE */
+        let mut modem_control = config.modem_control().bits();
+        if variant == Variant::Uart16750 && flow_control == FlowControl::AutoRtsCts
+        {
+            modem_control |= MODEM_CONTROL_AFE | MODEM_CONTROL_RTS;
+        }
+        uart.write_reg(REG_MODEM_CONTROL, modem_control);
 
-        /* enable IRQs */
-        uart.write_reg(REG_IRQ_EN, 1);
+        /* enable the requested IRQs */
+        uart.set_interrupt_enable(config.interrupts());
 
         Ok(uart)
     }
 
-    /* return size of this controller's MMIO space in bytes */
-    pub fn size(&self) -> usize
+    /* return a reference to the registers this UART drives */
+    pub fn registers(&self) -> &R
+    {
+        &self.regs
+    }
+
+    /* return the kind of chip this UART was set up as */
+    pub fn variant(&self) -> Variant
     {
-        REG_TOTAL_SIZE
+        self.variant
     }
 
-    /* centralize reading and writing of registers to these unsafe functions */
+    /* return the divisor programmed into the chip, including the baud rate it achieves */
+    pub fn divisor(&self) -> Divisor
+    {
+        self.divisor
+    }
+
+    /* all register accesses go through the backend */
     fn write_reg(&self, reg: usize, val: u8)
     {
-        /* assumes reg is within MMIO area's range */
-        unsafe { write_volatile((self.base_addr + reg) as *mut u8, val) }
+        self.regs.write(reg, val)
     }
 
     fn read_reg(&self, reg: usize) -> u8
     {
-        /* assumes reg is within MMIO area's range */
-        unsafe { read_volatile((self.base_addr + reg) as *const u8) }
+        self.regs.read(reg)
+    }
+
+    /* change how long send_byte() and read_byte() wait for the chip */
+    pub fn set_tx_timeout(&mut self, timeout: Timeout)
+    {
+        self.tx_timeout = timeout;
     }
-    
+
+    pub fn set_rx_timeout(&mut self, timeout: Timeout)
+    {
+        self.rx_timeout = timeout;
+    }
+
+    /* transmit a byte, waiting according to this UART's TX timeout for room to do so */
     pub fn send_byte(&self, to_send: u8) -> Result<(), Fault>
     {
-        let mut result = Err(Fault::TxNotEmpty);
-        let mut attempts_remaining = LOOP_MAX;
+        self.send_byte_timeout(to_send, self.tx_timeout)
+    }
+
+    /* transmit a byte, or fail with TxNotEmpty if there's no room, or the other end
+       isn't clear to receive it, before the timeout expires */
+    pub fn send_byte_timeout(&self, to_send: u8, timeout: Timeout) -> Result<(), Fault>
+    {
+        if !timeout.wait_until(|| self.ready_to_send()) { return Err(Fault::TxNotEmpty) }
+
+        self.write_reg(REG_DATA, to_send);
+        Ok(())
+    }
 
-        while attempts_remaining > 0
+    /* receive a byte, waiting according to this UART's RX timeout for one to arrive */
+    pub fn read_byte(&self) -> Result<u8, Fault>
+    {
+        self.read_byte_timeout(self.rx_timeout)
+    }
+
+    /* receive a byte, or fail with DataNotReady if none arrives before the timeout expires.
+       a byte received with a parity or framing error is returned inside the fault.
+       a break condition is returned as Fault::Break rather than as a 0x00 byte.
+       after an overrun, Fault::Overrun is returned once and the following bytes are
+       then received as normal, so a line with errors doesn't need resetting.
+       with XON/XOFF flow control, XON and XOFF are acted on and skipped over */
+    pub fn read_byte_timeout(&self, timeout: Timeout) -> Result<u8, Fault>
+    {
+        loop
         {
-            self.is_transmit_empty().then(||
-            {
-                self.write_reg(REG_DATA, to_send);
-                result = Ok(());
-                attempts_remaining = 0;
-            });
+            let byte = self.read_raw_byte(timeout)?;
+            if !self.handle_flow_control_char(byte) { return Ok(byte) }
         }
+    }
 
-        result
+    /* receive a byte from the chip, reporting any line errors */
+    fn read_raw_byte(&self, timeout: Timeout) -> Result<u8, Fault>
+    {
+        if self.overrun_pending.swap(false, Ordering::Relaxed) { return Err(Fault::Overrun) }
+
+        if !timeout.wait_until(|| self.is_data_ready()) { return Err(Fault::DataNotReady) }
+        self.take_byte()
     }
 
-    pub fn read_byte(&self) -> Result<u8, Fault>
+    /* read the byte at the front of the RX FIFO, which must have data ready, with its line errors */
+    fn take_byte(&self) -> Result<u8, Fault>
     {
-        if self.is_data_ready()
+        /* reading LSR clears its error bits, so they may have been picked up
+        by an earlier LSR read, eg while checking whether data could be sent */
+        let errors = self.rx_errors_pending.swap(0, Ordering::Relaxed);
+        let byte = self.read_reg(REG_DATA);
+
+        if errors & LINE_STATUS_BI != 0 { return Err(Fault::Break) }
+        if errors & LINE_STATUS_PE != 0 { return Err(Fault::Parity(byte)) }
+        if errors & LINE_STATUS_FE != 0 { return Err(Fault::Framing(byte)) }
+        Ok(byte)
+    }
+
+    /* return the number of line errors seen since the counts were last reset */
+    pub fn line_error_counts(&self) -> LineErrorCounts
+    {
+        self.line_errors.snapshot()
+    }
+
+    pub fn reset_line_error_counts(&self)
+    {
+        self.line_errors.reset()
+    }
+
+    /* throw away everything in the RX FIFO, including any bytes with errors */
+    pub fn clear_rx_fifo(&self)
+    {
+        self.write_reg(REG_FIFO_CONTROL, self.fifo_control | FIFO_CONTROL_RX_RESET);
+        self.rx_errors_pending.store(0, Ordering::Relaxed);
+    }
+
+    /* return the number of bytes that can be written to the chip in one go when THRE is set */
+    fn tx_fifo_depth(&self) -> usize
+    {
+        if self.fifo_control & FIFO_CONTROL_ENABLE != 0 { self.variant.fifo_depth() } else { 1 }
+    }
+
+    /* read the line status register, and keep hold of any errors it
+       reports because reading the register clears them */
+    fn read_line_status(&self) -> u8
+    {
+        let status = self.read_reg(REG_LINE_STATUS);
+        self.line_errors.record(status);
+
+        if status & LINE_STATUS_OE != 0
+        {
+            self.overrun_pending.store(true, Ordering::Relaxed);
+        }
+
+        if status & LINE_STATUS_DR != 0 && status & LINE_STATUS_BYTE_ERRORS != 0
         {
-            return Ok(self.read_reg(REG_DATA))
+            self.rx_errors_pending.fetch_or(status & LINE_STATUS_BYTE_ERRORS, Ordering::Relaxed);
         }
 
-        Err(Fault::DataNotReady)
+        status
     }
 
     /* return true if data can be sent */
     fn is_transmit_empty(&self) -> bool
     {
-        let val = self.read_reg(REG_LINE_STATUS);
-        return val & LINE_STATUS_THRE != 0
+        self.read_line_status() & LINE_STATUS_THRE != 0
+    }
+
+    /* return true if there's room to send data and, if we're watching CTS, the other end will take it */
+    fn ready_to_send(&self) -> bool
+    {
+        self.is_transmit_empty() && self.clear_to_send()
+    }
+
+    /* return true if the next byte in the RX FIFO can be read without reporting an error */
+    fn clean_data_ready(&self) -> bool
+    {
+        self.is_data_ready()
+            && self.rx_errors_pending.load(Ordering::Relaxed) == 0
+            && !self.overrun_pending.load(Ordering::Relaxed)
+    }
+
+    /* return true if the last byte has left the transmitter */
+    fn is_transmitter_idle(&self) -> bool
+    {
+        self.read_line_status() & LINE_STATUS_TEMT != 0
     }
 
     /* return true if data is ready to be read */
     fn is_data_ready(&self) -> bool
     {
-        let val = self.read_reg(REG_LINE_STATUS);
-        return val & LINE_STATUS_DR != 0
+        self.read_line_status() & LINE_STATUS_DR != 0
     }
 }
 
 #[cfg(test)]
 mod tests
 {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+
+    /* record register writes and feed received bytes to the driver */
+    #[derive(Default)]
+    struct Mock
+    {
+        writes: RefCell<Vec<(usize, u8)>>,
+        rx: RefCell<VecDeque<u8>>,
+        tx_busy: bool
+    }
+
+    impl Registers for Mock
+    {
+        fn read(&self, reg: usize) -> u8
+        {
+            match reg
+            {
+                REG_DATA => self.rx.borrow_mut().pop_front().unwrap_or(0),
+                REG_LINE_STATUS =>
+                {
+                    let mut lsr = 0;
+                    if !self.tx_busy { lsr |= LINE_STATUS_THRE }
+                    if !self.rx.borrow().is_empty() { lsr |= LINE_STATUS_DR }
+                    lsr
+                },
+                _ => 0
+            }
+        }
+
+        fn write(&self, reg: usize, val: u8)
+        {
+            self.writes.borrow_mut().push((reg, val))
+        }
+    }
+
+    /* the mock doesn't loop back its modem lines, so it would fail the presence check */
+    fn mock_config() -> Config
+    {
+        Config::default().with_presence_check(false)
+    }
+
     #[test]
     fn it_works()
     {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn size_follows_register_layout()
+    {
+        let config = Config::default().with_reg_shift(2).with_access_width(AccessWidth::Bits32);
+        assert_eq!(UART::with_config(0, 31, config).err(), Some(Fault::SizeTooSmall));
+        assert_eq!(UART::with_config(0, usize::MAX, Config::default().with_reg_shift(62)).err(), Some(Fault::InvalidConfig));
+
+        /* use ordinary memory to stand in for the registers. it can't loop back the modem lines */
+        let config = config.with_presence_check(false);
+        let mut registers = [0u32; REG_COUNT];
+        let uart = UART::with_config(registers.as_mut_ptr() as usize, 32, config).unwrap();
+        assert_eq!(uart.size(), 32);
+        assert_eq!(registers[REG_LINE_CONTROL], 0b0011);
+        assert_eq!(registers[REG_MODEM_CONTROL], 0b1011);
+    }
+
+    #[test]
+    fn initialization_sequence()
+    {
+        let mock = Mock::default();
+        let config = mock_config()
+            .with_clock_hz(24_000_000)
+            .with_baud_rate(115200)
+            .with_parity(Parity::Even)
+            .with_variant(Variant::Uart16550A);
+        UART::with_registers(&mock, config).unwrap();
+
+        assert_eq!(*mock.writes.borrow(), vec![
+            (REG_IRQ_EN, 0),
+            (REG_LINE_CONTROL, LINE_CONTROL_DLAB),
+            (REG_DIVISOR_LSB, 13),
+            (REG_DIVISOR_MSB, 0),
+            (REG_LINE_CONTROL, 0b0001_1011),
+            (REG_FIFO_CONTROL, 0xc7),
+            (REG_MODEM_CONTROL, 0b1011),
+            (REG_IRQ_EN, 1)
+        ]);
+    }
+
+    #[test]
+    fn send_and_read_bytes()
+    {
+        let mock = Mock::default();
+        let uart = UART::with_registers(&mock, mock_config()).unwrap();
+        mock.writes.borrow_mut().clear();
+
+        uart.send_byte(b'x').unwrap();
+        assert_eq!(*mock.writes.borrow(), vec![(REG_DATA, b'x')]);
+
+        assert_eq!(uart.read_byte(), Err(Fault::DataNotReady));
+        mock.rx.borrow_mut().push_back(b'y');
+        assert_eq!(uart.read_byte(), Ok(b'y'));
+        assert_eq!(uart.read_byte(), Err(Fault::DataNotReady));
+    }
+
+    #[test]
+    fn stuck_transmitter_times_out()
+    {
+        let mock = Mock { tx_busy: true, ..Mock::default() };
+        let mut uart = UART::with_registers(&mock, mock_config()).unwrap();
+        mock.writes.borrow_mut().clear();
+
+        assert_eq!(uart.send_byte(b'x'), Err(Fault::TxNotEmpty));
+        uart.set_tx_timeout(Timeout::NonBlocking);
+        assert_eq!(uart.send_byte(b'x'), Err(Fault::TxNotEmpty));
+        assert!(mock.writes.borrow().is_empty());
+    }
+
+    #[test]
+    fn read_waits_for_data()
+    {
+        let mock = Mock::default();
+        let config = mock_config().with_rx_timeout(Timeout::Spins(10));
+        let uart = UART::with_registers(&mock, config).unwrap();
+
+        assert_eq!(uart.read_byte(), Err(Fault::DataNotReady));
+        mock.rx.borrow_mut().push_back(b'z');
+        assert_eq!(uart.read_byte_timeout(Timeout::NonBlocking), Ok(b'z'));
+    }
+
+    #[test]
+    fn line_errors_reported_and_counted()
+    {
+        use model::{Model, RxError};
+
+        let model = Model::new();
+        let uart = UART::with_registers(&model, Config::default()).unwrap();
+
+        model.receive_with_error(b'p', RxError::Parity);
+        model.receive_with_error(b'f', RxError::Framing);
+        model.receive_with_error(0, RxError::Break);
+        model.receive(b'k');
+
+        assert_eq!(uart.read_byte(), Err(Fault::Parity(b'p')));
+        assert_eq!(uart.read_byte(), Err(Fault::Framing(b'f')));
+        assert_eq!(uart.read_byte(), Err(Fault::Break));
+        assert_eq!(uart.read_byte(), Ok(b'k'));
+        assert_eq!(uart.line_error_counts(), LineErrorCounts { overrun: 0, parity: 1, framing: 1, breaks: 1 });
+
+        uart.reset_line_error_counts();
+        assert_eq!(uart.line_error_counts(), LineErrorCounts::default());
+    }
+
+    #[test]
+    fn recover_from_overrun()
+    {
+        let model = model::Model::new();
+        let uart = UART::with_registers(&model, Config::default()).unwrap();
+
+        /* one more byte than the FIFO can hold */
+        for byte in 0..17 { model.receive(byte) }
+
+        assert_eq!(uart.read_byte(), Ok(0));
+        assert_eq!(uart.read_byte(), Err(Fault::Overrun));
+        assert_eq!(uart.read_byte(), Ok(1));
+        assert_eq!(uart.line_error_counts().overrun, 1);
+
+        uart.clear_rx_fifo();
+        assert_eq!(uart.read_byte(), Err(Fault::DataNotReady));
+        model.receive(b'!');
+        assert_eq!(uart.read_byte(), Ok(b'!'));
+    }
 }
@@ -0,0 +1,69 @@
+/* keep track of errors reported by the line status register
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See README and LICENSE for usage and copying.
+ */
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use super::{LINE_STATUS_OE, LINE_STATUS_PE, LINE_STATUS_FE, LINE_STATUS_BI};
+
+/* number of each kind of line error seen since the counts were last reset.
+   lots of framing errors suggest the baud rate is wrong; parity errors
+   on their own suggest a noisy line; overruns mean bytes aren't being read fast enough */
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct LineErrorCounts
+{
+    pub overrun: u32,
+    pub parity: u32,
+    pub framing: u32,
+    pub breaks: u32
+}
+
+/* counters shared between interrupt and thread context */
+#[derive(Debug, Default)]
+pub(crate) struct LineErrorCounters
+{
+    overrun: AtomicU32,
+    parity: AtomicU32,
+    framing: AtomicU32,
+    breaks: AtomicU32
+}
+
+impl LineErrorCounters
+{
+    /* count the errors flagged in the given line status bits */
+    pub(crate) fn record(&self, line_status: u8)
+    {
+        let count = |counter: &AtomicU32, bit: u8|
+        {
+            if line_status & bit != 0 { counter.fetch_add(1, Ordering::Relaxed); }
+        };
+
+        count(&self.overrun, LINE_STATUS_OE);
+        count(&self.parity, LINE_STATUS_PE);
+        count(&self.breaks, LINE_STATUS_BI);
+
+        /* a break usually looks like a framing error too, so don't count it twice */
+        if line_status & LINE_STATUS_BI == 0 { count(&self.framing, LINE_STATUS_FE) }
+    }
+
+    pub(crate) fn snapshot(&self) -> LineErrorCounts
+    {
+        LineErrorCounts
+        {
+            overrun: self.overrun.load(Ordering::Relaxed),
+            parity: self.parity.load(Ordering::Relaxed),
+            framing: self.framing.load(Ordering::Relaxed),
+            breaks: self.breaks.load(Ordering::Relaxed)
+        }
+    }
+
+    pub(crate) fn reset(&self)
+    {
+        for counter in [&self.overrun, &self.parity, &self.framing, &self.breaks].iter()
+        {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
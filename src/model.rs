@@ -0,0 +1,209 @@
+/* behavioural software model of a 16550 for testing code on the host
+ *
+ * The model is built on the same emulated chip as virt::VirtualUART.
+ * Drive it through the Registers trait like real hardware, and use the
+ * methods below to play the part of whatever is on the other end of the wire.
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See README and LICENSE for usage and copying.
+ */
+
+use core::cell::RefCell;
+use super::*;
+use device::Device;
+
+/* errors that can be attached to a byte arriving at the model */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RxError
+{
+    Parity,
+    Framing,
+    Break   /* received as a 0x00 byte with the break interrupt bit set */
+}
+
+/* a 16550 in software. drive it with a UART via the Registers trait */
+pub struct Model
+{
+    state: RefCell<Device>
+}
+
+impl Default for Model
+{
+    fn default() -> Self { Model::new() }
+}
+
+impl Registers for Model
+{
+    fn read(&self, reg: usize) -> u8 { self.state.borrow_mut().load(reg) }
+    fn write(&self, reg: usize, val: u8) { self.state.borrow_mut().store(reg, val) }
+}
+
+impl Model
+{
+    /* create a model in its power-on state */
+    pub fn new() -> Self
+    {
+        Model { state: RefCell::new(Device::new()) }
+    }
+
+    /* deliver a byte from the other end of the wire. if there's no room in the
+       RX FIFO, the byte is lost and an overrun error is raised */
+    pub fn receive(&self, byte: u8)
+    {
+        self.state.borrow_mut().receive(byte, 0)
+    }
+
+    /* deliver a byte with a line error attached */
+    pub fn receive_with_error(&self, byte: u8, error: RxError)
+    {
+        let (byte, errors) = match error
+        {
+            RxError::Parity => (byte, LINE_STATUS_PE),
+            RxError::Framing => (byte, LINE_STATUS_FE),
+            RxError::Break => (0, LINE_STATUS_BI)
+        };
+        self.state.borrow_mut().receive(byte, errors)
+    }
+
+    /* signal that no more bytes have arrived for a while, raising the character
+       timeout interrupt if there are bytes in the RX FIFO below the trigger level */
+    pub fn character_timeout(&self)
+    {
+        self.state.borrow_mut().character_timeout()
+    }
+
+    /* collect the next byte transmitted by the UART, if any */
+    pub fn take_tx(&self) -> Option<u8>
+    {
+        self.state.borrow_mut().take_tx()
+    }
+
+    /* while stalled, transmitted bytes sit in the TX FIFO and THRE stays clear.
+       unstalling sends them all out onto the wire */
+    pub fn set_tx_stalled(&self, stalled: bool)
+    {
+        self.state.borrow_mut().set_tx_stalled(stalled)
+    }
+
+    /* drive the modem input lines from the other end of the wire */
+    pub fn set_modem_inputs(&self, cts: bool, dsr: bool, ri: bool, dcd: bool)
+    {
+        let mut lines = 0;
+        if cts { lines |= MODEM_STATUS_CTS }
+        if dsr { lines |= MODEM_STATUS_DSR }
+        if ri { lines |= MODEM_STATUS_RI }
+        if dcd { lines |= MODEM_STATUS_DCD }
+        self.state.borrow_mut().set_modem_inputs(lines)
+    }
+
+    /* return true if the chip is asserting its interrupt line */
+    pub fn interrupt_pending(&self) -> bool
+    {
+        self.state.borrow().interrupt_pending()
+    }
+
+    /* inspect the chip's configuration */
+    pub fn divisor(&self) -> u16 { self.state.borrow().divisor() }
+    pub fn line_control(&self) -> u8 { self.state.borrow().line_control() }
+    pub fn modem_control(&self) -> ModemControl { self.state.borrow().modem_control() }
+    pub fn interrupt_enable(&self) -> u8 { self.state.borrow().interrupt_enable() }
+    pub fn fifo_enabled(&self) -> bool { self.state.borrow().fifo_enabled() }
+    pub fn rx_len(&self) -> usize { self.state.borrow().rx_len() }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn initialization()
+    {
+        let model = Model::new();
+        let config = Config::default().with_clock_hz(50_000_000).with_baud_rate(115200).with_parity(Parity::Odd);
+        UART::with_registers(&model, config).unwrap();
+
+        assert_eq!(model.divisor(), 27);
+        assert_eq!(model.line_control(), 0b0000_1011);
+        assert!(model.fifo_enabled());
+        assert_eq!(model.modem_control(), Config::default().modem_control());
+        assert_eq!(model.interrupt_enable(), IRQ_EN_RX_DATA);
+    }
+
+    #[test]
+    fn transmit_and_receive()
+    {
+        let model = Model::new();
+        let uart = UART::with_registers(&model, Config::default()).unwrap();
+
+        for byte in b"hi" { uart.send_byte(*byte).unwrap() }
+        assert_eq!(model.take_tx(), Some(b'h'));
+        assert_eq!(model.take_tx(), Some(b'i'));
+        assert_eq!(model.take_tx(), None);
+
+        model.receive(b'!');
+        assert_eq!(uart.read_byte(), Ok(b'!'));
+        assert_eq!(uart.read_byte(), Err(Fault::DataNotReady));
+    }
+
+    #[test]
+    fn loopback()
+    {
+        let model = Model::new();
+        let mut mcr = Config::default().modem_control();
+        mcr.loopback = true;
+        let uart = UART::with_registers(&model, Config::default().with_modem_control(mcr)).unwrap();
+
+        uart.send_byte(0x55).unwrap();
+        assert_eq!(model.take_tx(), None);
+        assert_eq!(uart.read_byte(), Ok(0x55));
+
+        /* RTS, DTR and OUT2 loop back to CTS, DSR and DCD */
+        assert_eq!(model.read(REG_MODEM_STATUS) & 0xf0, MODEM_STATUS_CTS | MODEM_STATUS_DSR | MODEM_STATUS_DCD);
+    }
+
+    #[test]
+    fn fifo_trigger_and_overrun()
+    {
+        let model = Model::new();
+        UART::with_registers(&model, Config::default().with_fifo_trigger(FifoTrigger::Bytes4)).unwrap();
+
+        for byte in 0..3 { model.receive(byte) }
+        assert!(!model.interrupt_pending());
+        model.character_timeout();
+        assert_eq!(model.read(REG_IRQ_ID), IRQ_ID_FIFOS_ENABLED | IRQ_ID_RX_TIMEOUT);
+        model.receive(3);
+        assert_eq!(model.read(REG_IRQ_ID), IRQ_ID_FIFOS_ENABLED | IRQ_ID_RX_DATA);
+
+        for byte in 4..17 { model.receive(byte) }
+        assert_eq!(model.rx_len(), FIFO_DEPTH);
+        assert_eq!(model.read(REG_LINE_STATUS) & LINE_STATUS_OE, LINE_STATUS_OE);
+        assert_eq!(model.read(REG_LINE_STATUS) & LINE_STATUS_OE, 0);
+    }
+
+    #[test]
+    fn interrupt_priority()
+    {
+        let model = Model::new();
+        UART::with_registers(&model, Config::default().with_fifo_trigger(FifoTrigger::Bytes1)).unwrap();
+        model.write(REG_IRQ_EN, IRQ_EN_RX_DATA | IRQ_EN_TX_EMPTY | IRQ_EN_LINE_STATUS | IRQ_EN_MODEM_STATUS);
+
+        model.set_modem_inputs(true, false, false, false);
+        model.receive_with_error(0x7f, RxError::Parity);
+        model.receive(b'a');
+
+        let id = |model: &Model| model.read(REG_IRQ_ID) & IRQ_ID_MASK;
+        assert_eq!(id(&model), IRQ_ID_LINE_STATUS);
+        assert_eq!(model.read(REG_LINE_STATUS) & (LINE_STATUS_PE | LINE_STATUS_RXFE), LINE_STATUS_PE | LINE_STATUS_RXFE);
+        assert_eq!(model.read(REG_DATA), 0x7f);
+        assert_eq!(id(&model), IRQ_ID_RX_DATA);
+        assert_eq!(model.read(REG_DATA), b'a');
+
+        /* reading IIR clears the THRE interrupt */
+        assert_eq!(id(&model), IRQ_ID_TX_EMPTY);
+        assert_eq!(id(&model), IRQ_ID_MODEM_STATUS);
+        assert_eq!(model.read(REG_MODEM_STATUS), MODEM_STATUS_CTS | MODEM_STATUS_DCTS);
+        assert_eq!(id(&model), IRQ_ID_NONE);
+    }
+}
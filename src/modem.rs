@@ -0,0 +1,177 @@
+/* drive the modem control outputs and read the modem status inputs
+ *
+ * Besides talking to modems, these lines are handy for detecting a cable being
+ * plugged in or pulled out, through DSR or DCD, and for driving external reset
+ * lines from DTR, RTS or the OUT pins. Enable modem status interrupts to have
+ * handle_interrupt() report changes as they happen.
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See README and LICENSE for usage and copying.
+ */
+
+use super::*;
+
+/* input lines read from the modem status register, and how they've changed since it was last read */
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ModemStatus
+{
+    pub cts: bool,          /* clear to send */
+    pub dsr: bool,          /* data set ready */
+    pub ri: bool,           /* ring indicator */
+    pub dcd: bool,          /* data carrier detect */
+    pub cts_changed: bool,
+    pub dsr_changed: bool,
+    pub ri_ended: bool,     /* RI went from asserted to deasserted */
+    pub dcd_changed: bool
+}
+
+impl ModemStatus
+{
+    /* convert to and from the modem status register's bit layout */
+    pub fn bits(&self) -> u8
+    {
+        let mut bits = 0;
+        if self.cts { bits |= MODEM_STATUS_CTS }
+        if self.dsr { bits |= MODEM_STATUS_DSR }
+        if self.ri { bits |= MODEM_STATUS_RI }
+        if self.dcd { bits |= MODEM_STATUS_DCD }
+        if self.cts_changed { bits |= MODEM_STATUS_DCTS }
+        if self.dsr_changed { bits |= MODEM_STATUS_DDSR }
+        if self.ri_ended { bits |= MODEM_STATUS_TERI }
+        if self.dcd_changed { bits |= MODEM_STATUS_DDCD }
+        bits
+    }
+
+    pub fn from_bits(bits: u8) -> Self
+    {
+        ModemStatus
+        {
+            cts: bits & MODEM_STATUS_CTS != 0,
+            dsr: bits & MODEM_STATUS_DSR != 0,
+            ri: bits & MODEM_STATUS_RI != 0,
+            dcd: bits & MODEM_STATUS_DCD != 0,
+            cts_changed: bits & MODEM_STATUS_DCTS != 0,
+            dsr_changed: bits & MODEM_STATUS_DDSR != 0,
+            ri_ended: bits & MODEM_STATUS_TERI != 0,
+            dcd_changed: bits & MODEM_STATUS_DDCD != 0
+        }
+    }
+
+    /* return true if any input changed */
+    pub fn changed(&self) -> bool
+    {
+        self.cts_changed || self.dsr_changed || self.ri_ended || self.dcd_changed
+    }
+}
+
+impl<R: Registers> UART<R>
+{
+    /* return the state of the modem control outputs and loopback mode */
+    pub fn modem_control(&self) -> ModemControl
+    {
+        ModemControl::from_bits(self.read_reg(REG_MODEM_CONTROL))
+    }
+
+    /* drive the modem control outputs and select loopback mode. automatic flow control,
+       where it's controlled from this register, is left as it was */
+    pub fn set_modem_control(&self, modem_control: ModemControl)
+    {
+        let afe = self.read_reg(REG_MODEM_CONTROL) & MODEM_CONTROL_AFE;
+        self.write_reg(REG_MODEM_CONTROL, afe | modem_control.bits());
+    }
+
+    /* change one modem control output. with RTS/CTS flow control, RTS is also driven by a BufferedUART */
+    pub fn set_dtr(&self, asserted: bool) { self.update_modem_control(|mcr| mcr.dtr = asserted) }
+    pub fn set_rts(&self, asserted: bool) { self.update_modem_control(|mcr| mcr.rts = asserted) }
+    pub fn set_out1(&self, asserted: bool) { self.update_modem_control(|mcr| mcr.out1 = asserted) }
+    pub fn set_out2(&self, asserted: bool) { self.update_modem_control(|mcr| mcr.out2 = asserted) }
+    pub fn set_loopback(&self, enabled: bool) { self.update_modem_control(|mcr| mcr.loopback = enabled) }
+
+    fn update_modem_control<F: FnOnce(&mut ModemControl)>(&self, update: F)
+    {
+        let mut modem_control = self.modem_control();
+        update(&mut modem_control);
+        self.set_modem_control(modem_control);
+    }
+
+    /* read the modem status inputs, and how they've changed since this was last called.
+       this acknowledges any modem status interrupt */
+    pub fn modem_status(&self) -> ModemStatus
+    {
+        let status = self.read_modem_status();
+        ModemStatus::from_bits(status | self.modem_changes_pending.swap(0, Ordering::Relaxed))
+    }
+
+    /* read the modem status register, and keep hold of any changes it
+       reports because reading the register clears them */
+    pub(crate) fn read_modem_status(&self) -> u8
+    {
+        let status = self.read_reg(REG_MODEM_STATUS);
+        self.modem_changes_pending.fetch_or(status & MODEM_STATUS_CHANGES, Ordering::Relaxed);
+        status
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use model::Model;
+
+    #[test]
+    fn drive_outputs()
+    {
+        let model = Model::new();
+        let uart = UART::with_registers(&model, Config::default()).unwrap();
+        assert_eq!(uart.modem_control(), Config::default().modem_control());
+
+        uart.set_dtr(false);
+        uart.set_out1(true);
+        assert_eq!(model.modem_control(), ModemControl { dtr: false, rts: true, out1: true, out2: true, loopback: false });
+
+        uart.set_modem_control(ModemControl::default());
+        assert_eq!(model.modem_control(), ModemControl::default());
+    }
+
+    #[test]
+    fn read_inputs()
+    {
+        let model = Model::new();
+        let uart = UART::with_registers(&model, Config::default()).unwrap();
+        assert_eq!(uart.modem_status(), ModemStatus::default());
+
+        /* a cable is plugged in */
+        model.set_modem_inputs(true, true, false, true);
+        let status = uart.modem_status();
+        assert!(status.dsr && status.dsr_changed && status.changed());
+        assert_eq!(status.bits(), 0xbb);
+        assert_eq!(ModemStatus::from_bits(0xbb), status);
+        assert!(!uart.modem_status().changed());
+
+        /* and reported by interrupt */
+        uart.set_interrupt_enable(InterruptEnable { modem_status: true, ..InterruptEnable::default() });
+        model.set_modem_inputs(true, false, false, false);
+        let status = uart.handle_interrupt().modem_status.unwrap();
+        assert!(status.cts && !status.dsr && status.dsr_changed && status.dcd_changed && !status.cts_changed);
+    }
+
+    #[test]
+    fn changes_survive_cts_checks()
+    {
+        let model = Model::new();
+        let interrupts = InterruptEnable { modem_status: true, ..InterruptEnable::default() };
+        let config = Config::default().with_flow_control(FlowControl::RtsCts).with_interrupts(interrupts);
+        let uart = UART::with_registers(&model, config).unwrap();
+
+        /* checking CTS before sending reads MSR, and acknowledges the interrupt */
+        model.set_modem_inputs(true, true, false, true);
+        assert_eq!(uart.send_byte(b'x'), Ok(()));
+        assert!(!model.interrupt_pending());
+
+        let status = uart.handle_interrupt().modem_status.unwrap();
+        assert!(status.cts_changed && status.dsr_changed && status.dcd_changed);
+        assert!(!uart.modem_status().changed());
+        assert!(!uart.handle_interrupt().any());
+    }
+}
@@ -0,0 +1,380 @@
+/* share one UART between several consoles, eg a hypervisor's and its guests'
+ *
+ * Each console is a Channel with its own RX and TX rings. Output from every
+ * channel is interleaved onto the UART a line at a time, each line starting
+ * with the channel's prefix, which can include ANSI colour codes. Input goes
+ * to whichever channel has focus, which the user switches by typing Ctrl-A
+ * followed by:
+ *
+ *   0 to 9    the guest channel with that number
+ *   n         the next channel along, wrapping round through the control channel
+ *   c         the hypervisor's control channel
+ *   Ctrl-A    send a Ctrl-A to the channel with focus
+ *
+ * Anything else after Ctrl-A is ignored. The control channel has focus to start with.
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See README and LICENSE for usage and copying.
+ */
+
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use super::*;
+
+/* Ctrl-A, which starts a command to the multiplexer */
+pub const MUX_ESCAPE: u8 = 0x01;
+
+/* one console multiplexed onto the UART */
+#[derive(Debug)]
+pub struct Channel<'a>
+{
+    prefix: &'a str,            /* written at the start of each line of this channel's output */
+    rx: &'a RingBuffer<'a>,     /* input typed while this channel has focus */
+    tx: &'a RingBuffer<'a>,     /* output waiting to go out on the UART */
+    prefix_sent: AtomicUsize,   /* bytes of the prefix sent for the current line */
+    mid_line: AtomicBool        /* part of a line of this channel's output has gone out */
+}
+
+impl<'a> Channel<'a>
+{
+    pub const fn new(prefix: &'a str, rx: &'a RingBuffer<'a>, tx: &'a RingBuffer<'a>) -> Self
+    {
+        Channel { prefix, rx, tx, prefix_sent: AtomicUsize::new(0), mid_line: AtomicBool::new(false) }
+    }
+
+    /* queue output to go out on the UART when the multiplexer is next polled,
+       returning how many bytes there was room for */
+    pub fn write(&self, data: &[u8]) -> usize
+    {
+        self.tx.push_slice(data)
+    }
+
+    /* fetch input typed while this channel had focus, returning how many bytes were fetched */
+    pub fn read(&self, buf: &mut [u8]) -> usize
+    {
+        self.rx.pop_into(buf)
+    }
+
+    pub fn prefix(&self) -> &'a str
+    {
+        self.prefix
+    }
+}
+
+/* the channel that receives input */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Focus
+{
+    Control,
+    Guest(usize)    /* index into the guest channels */
+}
+
+/* a UART shared between a control channel and a set of guest channels. poll it from one
+   context, eg the UART's IRQ handler or the hypervisor's main loop, while each channel's
+   owner reads and writes its channel */
+#[derive(Debug)]
+pub struct ConsoleMux<'a, R: Registers = Mmio>
+{
+    uart: UART<R>,
+    control: &'a Channel<'a>,
+    guests: &'a [Channel<'a>],
+    focus: AtomicUsize,         /* index of the focused channel, where the control channel comes after the guests */
+    tx_channel: AtomicUsize,    /* index of the channel whose output was last written */
+    escaped: AtomicBool,        /* the last byte received was MUX_ESCAPE */
+    rx_dropped: AtomicU32       /* input lost because the focused channel's RX ring was full */
+}
+
+impl<'a, R: Registers> ConsoleMux<'a, R>
+{
+    /* take over an initialized UART, with the control channel in focus */
+    pub fn new(uart: UART<R>, control: &'a Channel<'a>, guests: &'a [Channel<'a>]) -> Self
+    {
+        ConsoleMux
+        {
+            uart,
+            control,
+            guests,
+            focus: AtomicUsize::new(guests.len()),
+            tx_channel: AtomicUsize::new(guests.len()),
+            escaped: AtomicBool::new(false),
+            rx_dropped: AtomicU32::new(0)
+        }
+    }
+
+    /* access the underlying UART, eg for its error counts */
+    pub fn uart(&self) -> &UART<R>
+    {
+        &self.uart
+    }
+
+    pub fn focus(&self) -> Focus
+    {
+        self.index_to_focus(self.focus.load(Ordering::Relaxed))
+    }
+
+    /* move input to the given channel, or fail with InvalidConfig if there's no such guest */
+    pub fn set_focus(&self, focus: Focus) -> Result<(), Fault>
+    {
+        let index = match focus
+        {
+            Focus::Control => self.guests.len(),
+            Focus::Guest(index) if index < self.guests.len() => index,
+            Focus::Guest(_) => return Err(Fault::InvalidConfig)
+        };
+        self.focus.store(index, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /* return the number of input bytes lost because a channel's RX ring was full */
+    pub fn rx_dropped(&self) -> u32
+    {
+        self.rx_dropped.load(Ordering::Relaxed)
+    }
+
+    /* pass everything received by the UART to the channel with focus, acting on any commands
+       to the multiplexer. returns the new focus if the user switched channels, so the caller
+       can say so on the control channel */
+    pub fn poll_input(&self) -> Option<Focus>
+    {
+        let mut switched = None;
+        let mut buf = [0u8; FIFO_DEPTH];
+        loop
+        {
+            let received = self.uart.read_into(&mut buf);
+            if received == 0 { break }
+
+            for byte in &buf[..received]
+            {
+                if let Some(focus) = self.route_input(*byte) { switched = Some(focus) }
+            }
+        }
+        switched
+    }
+
+    /* write out up to a line of queued output from each channel in turn, so a busy channel
+       can't hold up the caller. a line cut short by another channel's output is ended, and
+       continued later with its prefix. if the UART fails to send, the byte it was sending
+       stays queued for the next poll */
+    pub fn poll_output(&self) -> Result<(), Fault>
+    {
+        let count = self.guests.len() + 1;
+        let start = self.tx_channel.load(Ordering::Relaxed);
+
+        for index in (0..count).map(|step| (start + step) % count)
+        {
+            let channel = self.channel(index);
+            if channel.tx.is_empty() { continue }
+
+            let last = self.tx_channel.load(Ordering::Relaxed);
+            if last != index
+            {
+                self.end_line(self.channel(last))?;
+                self.tx_channel.store(index, Ordering::Relaxed);
+            }
+
+            /* only what was queued before now, in case the channel's owner keeps writing */
+            for _ in 0..channel.tx.len()
+            {
+                match self.send_next(channel)?
+                {
+                    Some(b'\n') | None => break,
+                    Some(_) => ()
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /* send a channel's next byte of output, preceded by its prefix at the start of a line,
+       and return it, or None if nothing's queued. it's only dequeued once it's been sent */
+    fn send_next(&self, channel: &Channel<'a>) -> Result<Option<u8>, Fault>
+    {
+        let byte = match channel.tx.peek()
+        {
+            Some(byte) => byte,
+            None => return Ok(None)
+        };
+
+        let prefix = channel.prefix.as_bytes();
+        for (sent, prefix_byte) in prefix.iter().enumerate().skip(channel.prefix_sent.load(Ordering::Relaxed))
+        {
+            self.uart.send_byte(*prefix_byte)?;
+            channel.prefix_sent.store(sent + 1, Ordering::Relaxed);
+            channel.mid_line.store(true, Ordering::Relaxed);
+        }
+
+        self.put(byte)?;
+        channel.tx.pop();
+        channel.mid_line.store(true, Ordering::Relaxed);
+        if byte == b'\n' { self.start_line(channel) }
+        Ok(Some(byte))
+    }
+
+    /* finish a channel's line in progress, if any, so another channel's output can start on a new line */
+    fn end_line(&self, channel: &Channel<'a>) -> Result<(), Fault>
+    {
+        if channel.mid_line.load(Ordering::Relaxed)
+        {
+            self.put(b'\n')?;
+            self.start_line(channel);
+        }
+        Ok(())
+    }
+
+    fn start_line(&self, channel: &Channel<'a>)
+    {
+        channel.prefix_sent.store(0, Ordering::Relaxed);
+        channel.mid_line.store(false, Ordering::Relaxed);
+    }
+
+    /* deliver a byte of input, or act on it as part of a command. returns the new focus if it changed */
+    fn route_input(&self, byte: u8) -> Option<Focus>
+    {
+        if !self.escaped.swap(false, Ordering::Relaxed)
+        {
+            match byte
+            {
+                MUX_ESCAPE => self.escaped.store(true, Ordering::Relaxed),
+                _ => self.deliver(byte)
+            }
+            return None;
+        }
+
+        let count = self.guests.len() + 1;
+        let index = match byte
+        {
+            MUX_ESCAPE =>
+            {
+                self.deliver(byte);
+                return None;
+            },
+            b'c' => self.guests.len(),
+            b'n' => (self.focus.load(Ordering::Relaxed) + 1) % count,
+            b'0'..=b'9' if ((byte - b'0') as usize) < self.guests.len() => (byte - b'0') as usize,
+            _ => return None
+        };
+
+        self.focus.store(index, Ordering::Relaxed);
+        Some(self.index_to_focus(index))
+    }
+
+    /* pass a byte of input to the channel with focus */
+    fn deliver(&self, byte: u8)
+    {
+        if !self.channel(self.focus.load(Ordering::Relaxed)).rx.push(byte)
+        {
+            self.rx_dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /* send a byte of output, inserting a CR before an LF if the UART is configured to */
+    fn put(&self, byte: u8) -> Result<(), Fault>
+    {
+        if byte == b'\n' && self.uart.lf_to_crlf { self.uart.send_byte(b'\r')? }
+        self.uart.send_byte(byte)
+    }
+
+    fn channel(&self, index: usize) -> &Channel<'a>
+    {
+        self.guests.get(index).unwrap_or(self.control)
+    }
+
+    fn index_to_focus(&self, index: usize) -> Focus
+    {
+        if index < self.guests.len() { Focus::Guest(index) } else { Focus::Control }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use core::sync::atomic::AtomicU8;
+    use model::Model;
+
+    /* run test on a multiplexer over a model, with a control channel and two guests */
+    fn with_mux(prefixes: [&str; 3], config: Config, test: impl FnOnce(&Model, &ConsoleMux<&Model>, &Channel, &[Channel]))
+    {
+        let model = Model::new();
+        let slots: [[AtomicU8; 32]; 6] = Default::default();
+        let rings: Vec<RingBuffer> = slots.iter().map(|slots| RingBuffer::new(slots)).collect();
+        let control = Channel::new(prefixes[0], &rings[0], &rings[1]);
+        let guests = [Channel::new(prefixes[1], &rings[2], &rings[3]), Channel::new(prefixes[2], &rings[4], &rings[5])];
+        let mux = ConsoleMux::new(UART::with_registers(&model, config).unwrap(), &control, &guests);
+        test(&model, &mux, &control, &guests);
+    }
+
+    fn sent(model: &Model) -> Vec<u8>
+    {
+        core::iter::from_fn(|| model.take_tx()).collect()
+    }
+
+    fn input(channel: &Channel) -> Vec<u8>
+    {
+        let mut buf = [0u8; 8];
+        let fetched = channel.read(&mut buf);
+        buf[..fetched].to_vec()
+    }
+
+    #[test]
+    fn output_tagged_by_line()
+    {
+        with_mux(["[hv] ", "[0] ", "[1] "], Config::default(), |model, mux, control, guests|
+        {
+            /* a line from each channel per poll */
+            guests[0].write(b"hello\nagain\n");
+            guests[1].write(b"wor");
+            control.write(b"up\n");
+            mux.poll_output().unwrap();
+            mux.poll_output().unwrap();
+            assert_eq!(sent(model), b"[hv] up\n[0] hello\n[1] wor\n[0] again\n".to_vec());
+
+            /* a partial line carries on if nothing else gets in the way */
+            guests[1].write(b"ld");
+            mux.poll_output().unwrap();
+            guests[1].write(b"!\n");
+            mux.poll_output().unwrap();
+            assert_eq!(sent(model), b"[1] ld!\n".to_vec());
+        });
+    }
+
+    #[test]
+    fn input_follows_focus()
+    {
+        with_mux(["", "", ""], Config::default(), |model, mux, control, guests|
+        {
+            for byte in b"a\x011b\x01\x01" { model.receive(*byte) }
+            assert_eq!(mux.poll_input(), Some(Focus::Guest(1)));
+            assert_eq!(input(control), b"a");
+            assert_eq!(input(&guests[1]), b"b\x01");
+
+            /* no such guest, then back to the control channel, then round to the first guest */
+            for byte in b"\x019c\x01cd\x01ne" { model.receive(*byte) }
+            assert_eq!(mux.poll_input(), Some(Focus::Guest(0)));
+            assert_eq!(input(&guests[1]), b"c");
+            assert_eq!(input(control), b"d");
+            assert_eq!(input(&guests[0]), b"e");
+
+            assert_eq!(mux.set_focus(Focus::Guest(2)), Err(Fault::InvalidConfig));
+            assert_eq!(mux.set_focus(Focus::Control), Ok(()));
+            assert_eq!(mux.focus(), Focus::Control);
+        });
+    }
+
+    #[test]
+    fn output_survives_stalls()
+    {
+        with_mux(["", "[0] ", ""], Config::default().with_tx_timeout(Timeout::Spins(2)), |model, mux, _, guests|
+        {
+            model.set_tx_stalled(true);
+            guests[0].write(b"0123456789abcdef\n");
+            assert_eq!(mux.poll_output(), Err(Fault::TxNotEmpty));
+            assert_eq!(mux.poll_output(), Err(Fault::TxNotEmpty));
+
+            model.set_tx_stalled(false);
+            assert_eq!(mux.poll_output(), Ok(()));
+            assert_eq!(sent(model), b"[0] 0123456789abcdef\n".to_vec());
+        });
+    }
+}
@@ -0,0 +1,109 @@
+/* check that a 16550 is really behind a set of registers
+ *
+ * Firmware tables can describe serial ports that aren't fitted, and an empty
+ * bus or ordinary memory can look enough like a register file to fool a
+ * careless driver. A real chip holds what's written to its scratch register,
+ * and in loopback mode reflects its modem control outputs in its modem status
+ * inputs without putting anything on the wire.
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See README and LICENSE for usage and copying.
+ */
+
+use super::*;
+
+/* modem control outputs, and the modem status inputs they loop back to */
+const LOOPBACK_PATTERNS: [(ModemControl, u8); 2] =
+[
+    (ModemControl { dtr: false, rts: true, out1: false, out2: true, loopback: true }, MODEM_STATUS_CTS | MODEM_STATUS_DCD),
+    (ModemControl { dtr: true, rts: false, out1: true, out2: false, loopback: true }, MODEM_STATUS_DSR | MODEM_STATUS_RI)
+];
+
+/* modem status bits that reflect the input lines rather than their changes */
+const MODEM_STATUS_LINES: u8 = MODEM_STATUS_CTS | MODEM_STATUS_DSR | MODEM_STATUS_RI | MODEM_STATUS_DCD;
+
+/* return Ok if a 16550-compatible chip responds through regs, or fail with NoDevice.
+   the chip's registers are left as they were found, and nothing is transmitted */
+pub fn check_present<R: Registers>(regs: &R) -> Result<(), Fault>
+{
+    /* the 8250 has no scratch register, so fall back to the interrupt enable bits */
+    let holds_values = holds(regs, REG_SCRATCH, &[0x55, 0xaa]) || holds(regs, REG_IRQ_EN, &[0x00, 0x0f]);
+    if !holds_values || !loops_back(regs) { return Err(Fault::NoDevice) }
+    Ok(())
+}
+
+/* return true if reg reads back each of patterns after it's written, then restore it */
+fn holds<R: Registers>(regs: &R, reg: usize, patterns: &[u8]) -> bool
+{
+    let saved = regs.read(reg);
+    let held = patterns.iter().all(|pattern|
+    {
+        regs.write(reg, *pattern);
+        regs.read(reg) == *pattern
+    });
+    regs.write(reg, saved);
+    held
+}
+
+/* return true if the modem control outputs show up in the modem status in loopback mode */
+fn loops_back<R: Registers>(regs: &R) -> bool
+{
+    let modem_control = regs.read(REG_MODEM_CONTROL);
+    let looped = LOOPBACK_PATTERNS.iter().all(|(outputs, inputs)|
+    {
+        regs.write(REG_MODEM_CONTROL, outputs.bits());
+        regs.read(REG_MODEM_STATUS) & MODEM_STATUS_LINES == *inputs
+    });
+    regs.write(REG_MODEM_CONTROL, modem_control);
+
+    /* discard the modem status changes caused by the test */
+    regs.read(REG_MODEM_STATUS);
+    looped
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use model::Model;
+
+    /* registers that read back whatever was last written to them */
+    struct Memory([core::cell::Cell<u8>; REG_COUNT]);
+
+    impl Registers for Memory
+    {
+        fn read(&self, reg: usize) -> u8 { self.0[reg].get() }
+        fn write(&self, reg: usize, val: u8) { self.0[reg].set(val) }
+    }
+
+    /* a bus with nothing on it */
+    struct Floating;
+
+    impl Registers for Floating
+    {
+        fn read(&self, _reg: usize) -> u8 { 0xff }
+        fn write(&self, _reg: usize, _val: u8) {}
+    }
+
+    #[test]
+    fn model_is_present()
+    {
+        let model = Model::new();
+        model.write(REG_SCRATCH, 0x42);
+        assert_eq!(check_present(&model), Ok(()));
+        assert_eq!(model.read(REG_SCRATCH), 0x42);
+        assert_eq!(model.modem_control(), ModemControl::default());
+        assert!(UART::with_registers(&model, Config::default()).is_ok());
+    }
+
+    #[test]
+    fn nothing_there()
+    {
+        assert_eq!(UART::with_registers(Floating, Config::default()).err(), Some(Fault::NoDevice));
+
+        let memory = Memory(Default::default());
+        memory.write(REG_MODEM_STATUS, MODEM_STATUS_CTS | MODEM_STATUS_DCD);
+        assert_eq!(UART::with_registers(&memory, Config::default()).err(), Some(Fault::NoDevice));
+    }
+}
@@ -0,0 +1,135 @@
+/* receive everything waiting in the RX FIFO in one go
+ *
+ * Rather than looping over read_byte() until it fails with DataNotReady, drain
+ * what's already arrived into a buffer without waiting for more. Bytes the line
+ * status register flags as damaged are kept in place so the stream still lines
+ * up, and read_into_checked() says which ones they were.
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See README and LICENSE for usage and copying.
+ */
+
+use super::*;
+
+impl<R: Registers> UART<R>
+{
+    /* copy bytes that have already been received into buf, without waiting, until the chip
+       runs out or buf is full, and return how many were copied. a byte received with a parity
+       or framing error is copied as it was received, and a break is copied as 0x00. errors
+       are still counted by line_error_counts(), and an overrun is still reported by the next
+       read_byte(). with XON/XOFF flow control, XON and XOFF are acted on and skipped over */
+    pub fn read_into(&self, buf: &mut [u8]) -> usize
+    {
+        self.drain_rx_fifo(buf, |_, _| ())
+    }
+
+    /* as read_into(), and for each byte copied into buf, set the same element of errors to
+       the line error it was received with: Fault::Parity, Fault::Framing or Fault::Break, or
+       None if it arrived intact. no more bytes are copied than errors has room for */
+    pub fn read_into_checked(&self, buf: &mut [u8], errors: &mut [Option<Fault>]) -> usize
+    {
+        let len = buf.len().min(errors.len());
+        self.drain_rx_fifo(&mut buf[..len], |index, error| errors[index] = error)
+    }
+
+    /* for reads that wait for data: if a byte can be read, read it into buf, which mustn't be
+       empty, followed by as many more as can be read without waiting, stopping short of a byte
+       with a line error, and return how many were read. return None if there's nothing to
+       read yet, including when what arrived was only XON or XOFF */
+    pub(crate) fn read_available(&self, buf: &mut [u8]) -> Option<Result<usize, Fault>>
+    {
+        buf[0] = match self.read_byte_timeout(Timeout::NonBlocking)
+        {
+            Ok(byte) => byte,
+            Err(Fault::DataNotReady) => return None,
+            Err(error) => return Some(Err(error))
+        };
+
+        let mut fetched = 1;
+        while fetched < buf.len() && self.clean_data_ready()
+        {
+            /* this can only fail if the byte was XON or XOFF, leaving nothing to read */
+            match self.read_byte_timeout(Timeout::NonBlocking)
+            {
+                Ok(byte) => buf[fetched] = byte,
+                Err(_) => break
+            }
+            fetched += 1;
+        }
+        Some(Ok(fetched))
+    }
+
+    /* fill buf from the RX FIFO, passing the index and any error of each byte to record */
+    fn drain_rx_fifo<F: FnMut(usize, Option<Fault>)>(&self, buf: &mut [u8], mut record: F) -> usize
+    {
+        let mut count = 0;
+        while count < buf.len() && self.is_data_ready()
+        {
+            let (byte, error) = match self.take_byte()
+            {
+                Ok(byte) if self.handle_flow_control_char(byte) => continue,
+                Ok(byte) => (byte, None),
+                Err(Fault::Parity(byte)) => (byte, Some(Fault::Parity(byte))),
+                Err(Fault::Framing(byte)) => (byte, Some(Fault::Framing(byte))),
+                Err(error) => (0, Some(error))
+            };
+
+            buf[count] = byte;
+            record(count, error);
+            count += 1;
+        }
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use model::{Model, RxError};
+
+    #[test]
+    fn drains_what_has_arrived()
+    {
+        let model = Model::new();
+        let uart = UART::with_registers(&model, Config::default()).unwrap();
+        let mut buf = [0xffu8; 8];
+        assert_eq!(uart.read_into(&mut buf), 0);
+
+        for byte in b"hello" { model.receive(*byte) }
+        assert_eq!(uart.read_into(&mut buf[..3]), 3);
+        assert_eq!(uart.read_into(&mut buf[3..]), 2);
+        assert_eq!(&buf[..5], b"hello");
+        assert_eq!(model.rx_len(), 0);
+
+        /* flow control characters are taken out */
+        let uart = UART::with_registers(&model, Config::default().with_flow_control(FlowControl::XonXoff)).unwrap();
+        for byte in &[b'a', flow_control::XOFF, b'b'] { model.receive(*byte) }
+        assert_eq!(uart.read_into(&mut buf), 2);
+        assert_eq!(&buf[..2], b"ab");
+    }
+
+    #[test]
+    fn flags_damaged_bytes()
+    {
+        let model = Model::new();
+        let uart = UART::with_registers(&model, Config::default()).unwrap();
+
+        model.receive(b'a');
+        model.receive_with_error(b'b', RxError::Parity);
+        model.receive_with_error(b'c', RxError::Framing);
+        model.receive_with_error(0, RxError::Break);
+        model.receive(b'd');
+
+        let mut buf = [0u8; 8];
+        let mut errors = [None; 4];
+        assert_eq!(uart.read_into_checked(&mut buf, &mut errors), 4);
+        assert_eq!(&buf[..4], b"abc\0");
+        assert_eq!(errors, [None, Some(Fault::Parity(b'b')), Some(Fault::Framing(b'c')), Some(Fault::Break)]);
+
+        assert_eq!(uart.read_into_checked(&mut buf, &mut errors), 1);
+        assert_eq!((buf[0], errors[0]), (b'd', None));
+        assert_eq!(uart.line_error_counts(), LineErrorCounts { parity: 1, framing: 1, breaks: 1, ..LineErrorCounts::default() });
+    }
+}
@@ -0,0 +1,92 @@
+/* access the 16550's registers
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See README and LICENSE for usage and copying.
+ */
+
+use core::ptr::{write_volatile, read_volatile};
+use super::{AccessWidth, MAX_REG_SHIFT};
+
+/* the UART driver reads and writes the chip's registers, numbered 0 to 7,
+   through this trait. implement it to drive a 16550 via port I/O,
+   a simulated device, or a mock that records accesses */
+pub trait Registers
+{
+    fn read(&self, reg: usize) -> u8;
+    fn write(&self, reg: usize, val: u8);
+}
+
+/* allow a UART to borrow its registers rather than own them */
+impl<R: Registers + ?Sized> Registers for &R
+{
+    fn read(&self, reg: usize) -> u8 { (**self).read(reg) }
+    fn write(&self, reg: usize, val: u8) { (**self).write(reg, val) }
+}
+
+/* registers mapped into memory from base_addr onwards,
+   (1 << reg_shift) bytes apart and accessed access_width at a time */
+#[derive(Debug, Clone, Copy)]
+pub struct Mmio
+{
+    base_addr: usize,
+    reg_shift: u8,
+    access_width: AccessWidth
+}
+
+impl Mmio
+{
+    /* reg_shift must be no more than MAX_REG_SHIFT */
+    pub fn new(base_addr: usize, reg_shift: u8, access_width: AccessWidth) -> Self
+    {
+        debug_assert!(reg_shift <= MAX_REG_SHIFT);
+        Mmio { base_addr, reg_shift, access_width }
+    }
+
+    /* return the size in bytes of this MMIO area given the number of registers in it */
+    pub fn size(&self, reg_count: usize) -> usize
+    {
+        reg_count.saturating_sub(1)
+            .saturating_mul(1usize.checked_shl(self.reg_shift as u32).unwrap_or(usize::MAX))
+            .saturating_add(self.access_width.bytes())
+    }
+
+    fn addr(&self, reg: usize) -> usize
+    {
+        self.base_addr.wrapping_add(reg.wrapping_shl(self.reg_shift as u32))
+    }
+}
+
+/* centralize reading and writing of MMIO registers to these unsafe functions */
+impl Registers for Mmio
+{
+    fn write(&self, reg: usize, val: u8)
+    {
+        /* assumes reg is within MMIO area's range */
+        let addr = self.addr(reg);
+        unsafe
+        {
+            match self.access_width
+            {
+                AccessWidth::Bits8 => write_volatile(addr as *mut u8, val),
+                AccessWidth::Bits16 => write_volatile(addr as *mut u16, val as u16),
+                AccessWidth::Bits32 => write_volatile(addr as *mut u32, val as u32)
+            }
+        }
+    }
+
+    fn read(&self, reg: usize) -> u8
+    {
+        /* assumes reg is within MMIO area's range. the upper bits of wider registers are ignored */
+        let addr = self.addr(reg);
+        unsafe
+        {
+            match self.access_width
+            {
+                AccessWidth::Bits8 => read_volatile(addr as *const u8),
+                AccessWidth::Bits16 => read_volatile(addr as *const u16) as u8,
+                AccessWidth::Bits32 => read_volatile(addr as *const u32) as u8
+            }
+        }
+    }
+}
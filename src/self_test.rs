@@ -0,0 +1,268 @@
+/* check the chip works using its internal loopback mode
+ *
+ * In loopback mode the transmitter feeds the receiver and the modem control
+ * outputs feed the modem status inputs, all inside the chip and without
+ * anything going out on the wire. If the self-test passes but the link
+ * doesn't work, suspect the cable or whatever's on the other end of it.
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See README and LICENSE for usage and copying.
+ */
+
+use super::*;
+
+/* bytes sent through the loopback, chosen to exercise every data bit both ways */
+const TEST_PATTERN: [u8; 8] = [0x00, 0xff, 0x55, 0xaa, 0x01, 0x80, 0x0f, 0xf0];
+
+/* loopback mode with all the modem control outputs deasserted */
+const LOOPBACK: ModemControl = ModemControl { dtr: false, rts: false, out1: false, out2: false, loopback: true };
+
+/* pairs of modem control outputs and the modem status inputs they drive in loopback mode */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoopbackSignal
+{
+    RtsToCts,
+    DtrToDsr,
+    Out1ToRi,
+    Out2ToDcd
+}
+
+const SIGNALS: [LoopbackSignal; 4] =
+[
+    LoopbackSignal::RtsToCts, LoopbackSignal::DtrToDsr, LoopbackSignal::Out1ToRi, LoopbackSignal::Out2ToDcd
+];
+
+impl LoopbackSignal
+{
+    /* modem control register with just this signal's output asserted, in loopback mode */
+    fn output(&self) -> ModemControl
+    {
+        let mut mcr = LOOPBACK;
+        match self
+        {
+            LoopbackSignal::RtsToCts => mcr.rts = true,
+            LoopbackSignal::DtrToDsr => mcr.dtr = true,
+            LoopbackSignal::Out1ToRi => mcr.out1 = true,
+            LoopbackSignal::Out2ToDcd => mcr.out2 = true
+        }
+        mcr
+    }
+
+    /* modem status bit of this signal's input */
+    fn input(&self) -> u8
+    {
+        match self
+        {
+            LoopbackSignal::RtsToCts => MODEM_STATUS_CTS,
+            LoopbackSignal::DtrToDsr => MODEM_STATUS_DSR,
+            LoopbackSignal::Out1ToRi => MODEM_STATUS_RI,
+            LoopbackSignal::Out2ToDcd => MODEM_STATUS_DCD
+        }
+    }
+}
+
+/* the first thing found wrong by self_test(). index is the position of the byte in the test pattern */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SelfTestFailure
+{
+    Transmit { index: usize, sent: u8 },                    /* the transmitter wouldn't take the byte */
+    Lost { index: usize, sent: u8 },                        /* the byte never came back */
+    Corrupted { index: usize, sent: u8, received: u8 },     /* the byte came back different or with a line error */
+    Signal { signal: LoopbackSignal, asserted: bool }       /* the input didn't follow its output to this level */
+}
+
+impl<R: Registers> UART<R>
+{
+    /* run the loopback self-test, waiting for each byte according to this UART's TX timeout */
+    pub fn self_test(&self) -> Result<(), Fault>
+    {
+        self.self_test_timeout(self.tx_timeout)
+    }
+
+    /* loop a pattern of bytes and each modem control line back through the chip, failing with
+       Fault::SelfTest describing the first fault found. anything waiting to be received is
+       thrown away, and the interrupt enable and modem control registers are restored afterwards.
+       timeout is how long to wait for each byte, which has to go through the transmitter
+       at the configured baud rate, to come back */
+    pub fn self_test_timeout(&self, timeout: Timeout) -> Result<(), Fault>
+    {
+        /* let whatever's being transmitted finish before the transmitter is disconnected */
+        if !timeout.wait_until(|| self.is_transmitter_idle()) { return Err(Fault::TxNotEmpty) }
+
+        let modem_control = self.read_reg(REG_MODEM_CONTROL);
+
+        /* an XOFF from the other end has no say over the loopback */
+        let tx_paused = self.tx_paused.swap(false, Ordering::Relaxed);
+
+        /* keep the IRQ handler from taking the looped-back bytes */
+        self.write_reg(REG_IRQ_EN, 0);
+        self.write_reg(REG_MODEM_CONTROL, LOOPBACK.bits());
+        self.discard_rx();
+
+        let result = self.loop_signals().and_then(|_| self.loop_bytes(timeout));
+
+        self.discard_rx();
+        self.write_reg(REG_MODEM_CONTROL, modem_control);
+
+        /* forget the modem status changes made by the test */
+        self.read_reg(REG_MODEM_STATUS);
+        self.tx_paused.store(tx_paused, Ordering::Relaxed);
+        self.write_irq_enable();
+
+        result.map_err(Fault::SelfTest)
+    }
+
+    /* assert each output in turn and check only its input follows */
+    fn loop_signals(&self) -> Result<(), SelfTestFailure>
+    {
+        for driven in SIGNALS.iter()
+        {
+            for asserted in [true, false].iter()
+            {
+                let mcr = if *asserted { driven.output() } else { LOOPBACK };
+                self.write_reg(REG_MODEM_CONTROL, mcr.bits());
+
+                let status = self.read_reg(REG_MODEM_STATUS);
+                for signal in SIGNALS.iter()
+                {
+                    let expected = *asserted && signal == driven;
+                    if (status & signal.input() != 0) != expected
+                    {
+                        return Err(SelfTestFailure::Signal { signal: *signal, asserted: expected });
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /* send each byte of the pattern through the loopback and check it comes back intact */
+    fn loop_bytes(&self, timeout: Timeout) -> Result<(), SelfTestFailure>
+    {
+        /* loop RTS back to CTS in case flow control is watching it */
+        self.write_reg(REG_MODEM_CONTROL, LoopbackSignal::RtsToCts.output().bits());
+
+        /* bits beyond the configured word length don't make it through */
+        let mask = 0xffu8 >> (3 - (self.read_reg(REG_LINE_CONTROL) & 0b11));
+
+        for (index, sent) in TEST_PATTERN.iter().map(|byte| byte & mask).enumerate()
+        {
+            if self.send_byte_timeout(sent, timeout).is_err()
+            {
+                return Err(SelfTestFailure::Transmit { index, sent });
+            }
+
+            /* a byte with a line error is corrupt even if its bits are right */
+            let (received, damaged) = match self.read_byte_timeout(timeout)
+            {
+                Ok(byte) => (byte, false),
+                Err(Fault::Parity(byte)) | Err(Fault::Framing(byte)) => (byte, true),
+                Err(Fault::Break) => (0, true),
+                Err(_) => return Err(SelfTestFailure::Lost { index, sent })
+            };
+
+            if received != sent || damaged
+            {
+                return Err(SelfTestFailure::Corrupted { index, sent, received });
+            }
+        }
+        Ok(())
+    }
+
+    /* empty the receiver and forget any errors it reported */
+    fn discard_rx(&self)
+    {
+        self.clear_rx_fifo();
+
+        /* the FIFO reset does nothing if the FIFOs are off */
+        for _ in 0..self.variant.fifo_depth()
+        {
+            if self.read_line_status() & LINE_STATUS_DR == 0 { break }
+            self.read_reg(REG_DATA);
+        }
+
+        self.rx_errors_pending.store(0, Ordering::Relaxed);
+        self.overrun_pending.store(false, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use model::Model;
+
+    /* a model with a fault between its registers and the CPU */
+    struct Faulty
+    {
+        model: Model,
+        data_flip: u8,      /* bits inverted in received bytes */
+        status_stuck: u8    /* modem status bits that always read as clear */
+    }
+
+    impl Registers for Faulty
+    {
+        fn read(&self, reg: usize) -> u8
+        {
+            match reg
+            {
+                REG_DATA if self.model.line_control() & LINE_CONTROL_DLAB == 0 => self.model.read(reg) ^ self.data_flip,
+                REG_MODEM_STATUS => self.model.read(reg) & !self.status_stuck,
+                _ => self.model.read(reg)
+            }
+        }
+
+        fn write(&self, reg: usize, val: u8) { self.model.write(reg, val) }
+    }
+
+    fn faulty(data_flip: u8, status_stuck: u8) -> Faulty
+    {
+        Faulty { model: Model::new(), data_flip, status_stuck }
+    }
+
+    #[test]
+    fn passes_and_restores_state()
+    {
+        let model = Model::new();
+        let interrupts = InterruptEnable { rx_data: true, modem_status: true, ..InterruptEnable::default() };
+        let uart = UART::with_registers(&model, Config::default().with_interrupts(interrupts)).unwrap();
+        model.receive(b'?');
+
+        assert_eq!(uart.self_test(), Ok(()));
+        assert_eq!(model.modem_control(), Config::default().modem_control());
+        assert_eq!(uart.interrupt_enable(), interrupts);
+        assert!(!model.interrupt_pending());
+        assert_eq!(model.take_tx(), None);
+        assert_eq!(uart.read_byte(), Err(Fault::DataNotReady));
+
+        /* the other end has paused us with XOFF, which still holds afterwards */
+        let uart = UART::with_registers(&model, Config::default().with_flow_control(FlowControl::XonXoff)).unwrap();
+        model.receive(flow_control::XOFF);
+        assert_eq!(uart.read_byte(), Err(Fault::DataNotReady));
+        assert_eq!(uart.self_test(), Ok(()));
+        assert_eq!(uart.send_byte(b'x'), Err(Fault::TxNotEmpty));
+
+        /* 7 data bits */
+        let uart = UART::with_registers(&model, Config::default().with_data_bits(DataBits::Seven)).unwrap();
+        assert_eq!(uart.self_test(), Ok(()));
+    }
+
+    #[test]
+    fn reports_bad_byte()
+    {
+        let chip = faulty(1 << 2, 0);
+        let uart = UART::with_registers(&chip, Config::default().with_variant(Variant::Uart16550A)).unwrap();
+        assert_eq!(uart.self_test(), Err(Fault::SelfTest(SelfTestFailure::Corrupted { index: 0, sent: 0, received: 4 })));
+    }
+
+    #[test]
+    fn reports_bad_signal()
+    {
+        let chip = faulty(0, MODEM_STATUS_DCD);
+        let config = Config::default().with_variant(Variant::Uart16550A).with_presence_check(false);
+        let uart = UART::with_registers(&chip, config).unwrap();
+        let failure = SelfTestFailure::Signal { signal: LoopbackSignal::Out2ToDcd, asserted: true };
+        assert_eq!(uart.self_test(), Err(Fault::SelfTest(failure)));
+    }
+}
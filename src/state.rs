@@ -0,0 +1,174 @@
+/* save, restore and shut down the chip's settings
+ *
+ * A hypervisor handing a UART to a guest and back, or a kernel suspending and
+ * resuming, needs to put the chip back exactly as it was. Only the registers
+ * that hold settings are saved: data in the FIFOs, pending interrupts, and line
+ * errors belong to whoever had the chip at the time and are thrown away.
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See README and LICENSE for usage and copying.
+ */
+
+use super::*;
+
+/* a snapshot of the chip's settings */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SavedState
+{
+    pub irq_enable: u8,
+    pub line_control: u8,
+    pub modem_control: u8,
+    pub fifo_control: u8,   /* FCR is write-only, so this is what the driver last wrote */
+    pub divisor: u16,
+    pub scratch: u8,        /* zero on chips without a scratch register */
+    pub efr: u8             /* enhanced features, or zero on chips without an EFR */
+}
+
+impl<R: Registers> UART<R>
+{
+    /* take a snapshot of the chip's settings, leaving them as they are */
+    pub fn save_state(&self) -> SavedState
+    {
+        let line_control = self.read_reg(REG_LINE_CONTROL);
+
+        self.write_reg(REG_LINE_CONTROL, line_control | LINE_CONTROL_DLAB);
+        let divisor = self.read_reg(REG_DIVISOR_LSB) as u16 | (self.read_reg(REG_DIVISOR_MSB) as u16) << 8;
+
+        let efr = if self.variant.has_efr()
+        {
+            self.write_reg(REG_LINE_CONTROL, LINE_CONTROL_CONFIG_B);
+            self.read_reg(REG_EFR)
+        }
+        else { 0 };
+        self.write_reg(REG_LINE_CONTROL, line_control);
+
+        SavedState
+        {
+            irq_enable: self.irq_enable.load(Ordering::Relaxed),
+            line_control,
+            modem_control: self.read_reg(REG_MODEM_CONTROL),
+            fifo_control: self.fifo_control,
+            divisor,
+            scratch: if self.variant.has_scratch() { self.read_reg(REG_SCRATCH) } else { 0 },
+            efr
+        }
+    }
+
+    /* put back settings taken by save_state() on this UART. interrupts are held off until
+       everything else is in place, and the FIFOs are emptied along with any pending errors */
+    pub fn restore_state(&self, state: &SavedState)
+    {
+        self.write_reg(REG_IRQ_EN, 0);
+
+        if self.variant.has_efr()
+        {
+            self.write_reg(REG_LINE_CONTROL, LINE_CONTROL_CONFIG_B);
+            self.write_reg(REG_EFR, state.efr);
+        }
+
+        /* the divisor, and a 16750's FIFO size, can only be set while DLAB is set */
+        self.write_reg(REG_LINE_CONTROL, LINE_CONTROL_DLAB);
+        self.write_reg(REG_DIVISOR_LSB, (state.divisor & 0xff) as u8);
+        self.write_reg(REG_DIVISOR_MSB, (state.divisor >> 8) as u8);
+        if self.variant == Variant::Uart16750 { self.write_reg(REG_FIFO_CONTROL, state.fifo_control) }
+        self.write_reg(REG_LINE_CONTROL, state.line_control);
+
+        self.write_reg(REG_FIFO_CONTROL, state.fifo_control | FIFO_CONTROL_RX_RESET | FIFO_CONTROL_TX_RESET);
+        self.write_reg(REG_MODEM_CONTROL, state.modem_control);
+        if self.variant.has_scratch() { self.write_reg(REG_SCRATCH, state.scratch) }
+
+        self.forget_line_state();
+        self.set_interrupt_enable(InterruptEnable::from_bits(state.irq_enable));
+    }
+
+    /* leave the chip quiet: let the transmitter finish, as far as the TX timeout allows,
+       then disable interrupts, stop any break, empty the FIFOs, and deassert the modem
+       control outputs, including OUT2, which gates the IRQ line on many boards.
+       restore_state() or a new UART object brings it back */
+    pub fn shutdown(&self)
+    {
+        self.flush().ok();
+
+        self.set_interrupt_enable(InterruptEnable::default());
+        self.write_reg(REG_LINE_CONTROL, self.read_reg(REG_LINE_CONTROL) & !(LINE_CONTROL_BREAK | LINE_CONTROL_DLAB));
+        self.write_reg(REG_FIFO_CONTROL, self.fifo_control | FIFO_CONTROL_RX_RESET | FIFO_CONTROL_TX_RESET);
+        self.write_reg(REG_MODEM_CONTROL, 0);
+        self.forget_line_state();
+    }
+
+    /* clear the chip's status registers, and forget errors and flow control seen before now */
+    fn forget_line_state(&self)
+    {
+        self.read_reg(REG_LINE_STATUS);
+        self.read_reg(REG_MODEM_STATUS);
+        self.rx_errors_pending.store(0, Ordering::Relaxed);
+        self.modem_changes_pending.store(0, Ordering::Relaxed);
+        self.overrun_pending.store(false, Ordering::Relaxed);
+        self.tx_paused.store(false, Ordering::Relaxed);
+    }
+}
+
+impl<R: Registers> Drop for UART<R>
+{
+    fn drop(&mut self)
+    {
+        if self.shutdown_on_drop { self.shutdown() }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use model::Model;
+
+    #[test]
+    fn save_and_restore()
+    {
+        let model = Model::new();
+        let interrupts = InterruptEnable { rx_data: true, ..InterruptEnable::default() };
+        let config = Config::default().with_baud_rate(115200).with_data_bits(DataBits::Seven).with_interrupts(interrupts);
+        let uart = UART::with_registers(&model, config).unwrap();
+        model.write(REG_SCRATCH, 0x42);
+
+        let state = uart.save_state();
+        assert_eq!(state.divisor, 1);
+        assert_eq!(model.line_control(), state.line_control);
+
+        /* someone else takes the chip and sets it up their way */
+        let guest = UART::with_registers(&model, Config::default().with_baud_rate(9600)).unwrap();
+        model.write(REG_SCRATCH, 0);
+        guest.set_break(true);
+        model.receive(b'x');
+
+        uart.restore_state(&state);
+        assert_eq!(uart.save_state(), state);
+        assert_eq!(model.divisor(), 1);
+        assert_eq!(model.interrupt_enable(), interrupts.bits());
+        assert_eq!(model.rx_len(), 0);
+        assert_eq!(uart.read_byte(), Err(Fault::DataNotReady));
+    }
+
+    #[test]
+    fn shutdown()
+    {
+        let model = Model::new();
+        let config = Config::default().with_interrupts(InterruptEnable { rx_data: true, ..InterruptEnable::default() });
+        let uart = UART::with_registers(&model, config).unwrap();
+        uart.set_break(true);
+        uart.shutdown();
+        assert_eq!(model.interrupt_enable(), 0);
+        assert_eq!(model.modem_control(), ModemControl::default());
+        assert_eq!(model.line_control() & LINE_CONTROL_BREAK, 0);
+
+        /* only on drop if asked */
+        let uart = UART::with_registers(&model, Config::default()).unwrap();
+        drop(uart);
+        assert_eq!(model.modem_control(), Config::default().modem_control());
+
+        let uart = UART::with_registers(&model, Config::default().with_shutdown_on_drop(true)).unwrap();
+        drop(uart);
+        assert_eq!(model.modem_control(), ModemControl::default());
+    }
+}
@@ -0,0 +1,134 @@
+/* decide how long to wait for the UART
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See README and LICENSE for usage and copying.
+ */
+
+use core::fmt;
+
+/* a monotonic clock supplied by the caller, counting in whatever ticks it likes */
+pub trait TimeSource: Sync
+{
+    fn now(&self) -> u64;
+}
+
+/* how long to keep checking for the UART to become ready before giving up */
+#[derive(Clone, Copy)]
+pub enum Timeout
+{
+    NonBlocking,            /* check once */
+    Spins(usize),           /* check up to this many times */
+    Deadline                /* keep checking until this many ticks have passed */
+    {
+        clock: &'static dyn TimeSource,
+        ticks: u64
+    }
+}
+
+impl Timeout
+{
+    /* call check until it returns something, or the timeout expires.
+       check is always called at least once */
+    pub(crate) fn wait_for<T>(&self, mut check: impl FnMut() -> Option<T>) -> Option<T>
+    {
+        match *self
+        {
+            Timeout::NonBlocking => check(),
+            Timeout::Spins(spins) =>
+            {
+                for _ in 0..spins.max(1)
+                {
+                    if let Some(result) = check() { return Some(result) }
+                }
+                None
+            },
+            Timeout::Deadline { clock, ticks } =>
+            {
+                let start = clock.now();
+                loop
+                {
+                    if let Some(result) = check() { return Some(result) }
+                    if clock.now().wrapping_sub(start) >= ticks { return None }
+                }
+            }
+        }
+    }
+
+    /* call ready until it returns true, or return false if the timeout expires first */
+    pub(crate) fn wait_until(&self, mut ready: impl FnMut() -> bool) -> bool
+    {
+        self.wait_for(|| if ready() { Some(()) } else { None }).is_some()
+    }
+}
+
+impl fmt::Debug for Timeout
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self
+        {
+            Timeout::NonBlocking => write!(f, "NonBlocking"),
+            Timeout::Spins(spins) => write!(f, "Spins({})", spins),
+            Timeout::Deadline { ticks, .. } => write!(f, "Deadline {{ ticks: {} }}", ticks)
+        }
+    }
+}
+
+/* deadlines are equal if they use the same clock */
+impl PartialEq for Timeout
+{
+    fn eq(&self, other: &Self) -> bool
+    {
+        match (self, other)
+        {
+            (Timeout::NonBlocking, Timeout::NonBlocking) => true,
+            (Timeout::Spins(a), Timeout::Spins(b)) => a == b,
+            (Timeout::Deadline { clock: a, ticks: x }, Timeout::Deadline { clock: b, ticks: y }) =>
+                x == y && core::ptr::eq(*a as *const dyn TimeSource as *const u8, *b as *const dyn TimeSource as *const u8),
+            _ => false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    /* a clock that ticks every time it's read */
+    struct Ticker(AtomicU64);
+
+    impl TimeSource for Ticker
+    {
+        fn now(&self) -> u64 { self.0.fetch_add(1, Ordering::Relaxed) }
+    }
+
+    static TICKER: Ticker = Ticker(AtomicU64::new(0));
+
+    fn count_checks(timeout: Timeout) -> usize
+    {
+        let mut checks = 0;
+        let result: Option<()> = timeout.wait_for(|| { checks += 1; None });
+        assert_eq!(result, None);
+        checks
+    }
+
+    #[test]
+    fn policies_expire()
+    {
+        assert_eq!(count_checks(Timeout::NonBlocking), 1);
+        assert_eq!(count_checks(Timeout::Spins(0)), 1);
+        assert_eq!(count_checks(Timeout::Spins(25)), 25);
+        assert_eq!(count_checks(Timeout::Deadline { clock: &TICKER, ticks: 10 }), 10);
+    }
+
+    #[test]
+    fn stops_when_ready()
+    {
+        let mut checks = 0;
+        let result = Timeout::Spins(100).wait_for(|| { checks += 1; if checks == 3 { Some(checks) } else { None } });
+        assert_eq!(result, Some(3));
+    }
+}
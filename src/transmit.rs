@@ -0,0 +1,107 @@
+/* transmit slices of bytes a FIFO-full at a time
+ *
+ * When THRE is set the whole TX FIFO is empty, so rather than checking the
+ * line status before every byte, fill the FIFO in one go after each check.
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See README and LICENSE for usage and copying.
+ */
+
+use super::*;
+
+impl<R: Registers> UART<R>
+{
+    /* wait, according to this UART's TX timeout, for the TX FIFO to empty, then fill it
+       from data and return the number of bytes written. fails with TxNotEmpty if the FIFO
+       doesn't empty, or the other end isn't clear to receive, before the timeout expires */
+    pub fn send(&self, data: &[u8]) -> Result<usize, Fault>
+    {
+        if data.is_empty() { return Ok(0) }
+        if !self.tx_timeout.wait_until(|| self.ready_to_send()) { return Err(Fault::TxNotEmpty) }
+        Ok(self.fill_tx_fifo(data))
+    }
+
+    /* transmit all of data, a FIFO-full at a time */
+    pub fn write_all(&self, mut data: &[u8]) -> Result<(), Fault>
+    {
+        while !data.is_empty()
+        {
+            let sent = self.send(data)?;
+            data = &data[sent..];
+        }
+        Ok(())
+    }
+
+    /* wait, according to this UART's TX timeout, for the last byte to leave the
+       transmitter's shift register, eg before resetting or powering down */
+    pub fn flush(&self) -> Result<(), Fault>
+    {
+        match self.tx_timeout.wait_until(|| self.is_transmitter_idle())
+        {
+            true => Ok(()),
+            false => Err(Fault::TxNotEmpty)
+        }
+    }
+
+    /* write as much of data as fits into the TX FIFO, which must be empty, and return how much was written */
+    pub(crate) fn fill_tx_fifo(&self, data: &[u8]) -> usize
+    {
+        let burst = data.len().min(self.tx_fifo_depth());
+        for byte in &data[..burst]
+        {
+            self.write_reg(REG_DATA, *byte);
+        }
+        burst
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use core::cell::Cell;
+    use model::Model;
+
+    /* count line status reads */
+    struct Counter
+    {
+        model: Model,
+        lsr_reads: Cell<usize>
+    }
+
+    impl Registers for Counter
+    {
+        fn read(&self, reg: usize) -> u8
+        {
+            if reg == REG_LINE_STATUS { self.lsr_reads.set(self.lsr_reads.get() + 1) }
+            self.model.read(reg)
+        }
+
+        fn write(&self, reg: usize, val: u8) { self.model.write(reg, val) }
+    }
+
+    #[test]
+    fn send_in_bursts()
+    {
+        let chip = Counter { model: Model::new(), lsr_reads: Cell::new(0) };
+        let uart = UART::with_registers(&chip, Config::default().with_presence_check(false)).unwrap();
+        let message: Vec<u8> = (0..40).collect();
+
+        /* one line status check per FIFO-full */
+        chip.lsr_reads.set(0);
+        uart.write_all(&message).unwrap();
+        assert_eq!(chip.lsr_reads.get(), 3);
+        uart.flush().unwrap();
+        let sent: Vec<u8> = core::iter::from_fn(|| chip.model.take_tx()).collect();
+        assert_eq!(sent, message);
+
+        chip.model.set_tx_stalled(true);
+        assert_eq!(uart.send(&message), Ok(16));
+        assert_eq!(uart.send(&message[16..]), Err(Fault::TxNotEmpty));
+        assert_eq!(uart.flush(), Err(Fault::TxNotEmpty));
+        chip.model.set_tx_stalled(false);
+        assert_eq!(uart.flush(), Ok(()));
+        assert_eq!(uart.send(&[]), Ok(0));
+    }
+}
@@ -0,0 +1,391 @@
+/* identify which member of the 8250 family is behind the registers
+ *
+ * The probe follows the classic sequence: a scratch register test separates
+ * the 8250 from later parts, the FIFO bits in IIR separate the 16450, the
+ * original 16550 and the 16550A, and then the EFR, the 16750's 64-byte FIFO
+ * bit, and the device IDs of the 16850 and 16950 pick out the larger chips.
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See README and LICENSE for usage and copying.
+ */
+
+use super::*;
+
+/* the 16950's indexed control register (ICR) shares its offset with LSR,
+   and is selected by writing its index to the scratch register */
+const REG_ICR: usize = 5;
+const ICR_ACR: u8 = 0x00;               /* additional control register */
+const ICR_ID1: u8 = 0x08;               /* device ID registers */
+const ICR_ID2: u8 = 0x09;
+const ICR_ID3: u8 = 0x0a;
+const ACR_ICR_READ: u8 = 1 << 6;        /* make ICR readable through LSR's offset */
+
+/* the 16850's device ID, readable from DLM while the divisor is zero. DLL holds its revision */
+const XR16850_IDS: [u8; 3] = [0x10, 0x12, 0x14];
+
+/* 16550-compatible chip families, which differ in their FIFOs and extra features */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Variant
+{
+    Uart8250,   /* no FIFOs and no scratch register */
+    Uart16450,  /* no FIFOs */
+    Uart16550,  /* the original 16550, whose FIFOs don't work so are left disabled */
+    Uart16550A, /* working 16-byte FIFOs */
+    Uart16650,  /* 32-byte FIFOs and an enhanced feature register (EFR) */
+    Uart16750,  /* 64-byte FIFOs and automatic RTS/CTS flow control */
+    Uart16850,  /* 128-byte FIFOs and an EFR */
+    Uart16950   /* 128-byte FIFOs, an EFR and indexed control registers */
+}
+
+impl Variant
+{
+    /* identify the chip behind regs. this leaves its interrupts and FIFOs disabled,
+       so it's best done before the chip is initialized, as UART::with_registers() does */
+    pub fn probe<R: Registers>(regs: &R) -> Variant
+    {
+        /* offset 1 is DLM rather than IER if DLAB was left set */
+        let line_control = regs.read(REG_LINE_CONTROL);
+        regs.write(REG_LINE_CONTROL, 0);
+        regs.write(REG_IRQ_EN, 0);
+        let scratch = regs.read(REG_SCRATCH);
+
+        let variant = identify(regs);
+
+        regs.write(REG_FIFO_CONTROL, 0);
+        regs.write(REG_LINE_CONTROL, line_control);
+        regs.write(REG_SCRATCH, scratch);
+        variant
+    }
+
+    /* return the number of bytes each of the TX and RX FIFOs holds, or 1 if they aren't used */
+    pub fn fifo_depth(&self) -> usize
+    {
+        match self
+        {
+            Variant::Uart8250 | Variant::Uart16450 | Variant::Uart16550 => 1,
+            Variant::Uart16550A => FIFO_DEPTH,
+            Variant::Uart16650 => 32,
+            Variant::Uart16750 => 64,
+            Variant::Uart16850 | Variant::Uart16950 => 128
+        }
+    }
+
+    pub fn has_fifo(&self) -> bool { self.fifo_depth() > 1 }
+    pub fn has_scratch(&self) -> bool { *self != Variant::Uart8250 }
+
+    /* return true if the chip has an enhanced feature register */
+    pub fn has_efr(&self) -> bool
+    {
+        matches!(self, Variant::Uart16650 | Variant::Uart16850 | Variant::Uart16950)
+    }
+
+    /* return true if the chip can start and stop transmission from CTS, and drive RTS, by itself */
+    pub fn has_auto_flow_control(&self) -> bool
+    {
+        self.has_efr() || *self == Variant::Uart16750
+    }
+}
+
+fn identify<R: Registers>(regs: &R) -> Variant
+{
+    /* the 8250 has no scratch register */
+    let scratch_works = [0x55, 0xaa].iter().all(|pattern|
+    {
+        regs.write(REG_SCRATCH, *pattern);
+        regs.read(REG_SCRATCH) == *pattern
+    });
+
+    /* IIR reports whether the FIFOs could be enabled, and on the 16550, that they're broken */
+    regs.write(REG_FIFO_CONTROL, FIFO_CONTROL_ENABLE);
+    match regs.read(REG_IRQ_ID) & IRQ_ID_FIFOS_ENABLED
+    {
+        0 if scratch_works => return Variant::Uart16450,
+        0 => return Variant::Uart8250,
+        IRQ_ID_FIFOS_ENABLED => (),
+        _ => return Variant::Uart16550
+    }
+
+    if has_efr(regs) { return identify_efr(regs) }
+    if has_64byte_fifo(regs) { Variant::Uart16750 } else { Variant::Uart16550A }
+}
+
+/* without an EFR, the write goes to FCR and turns the FIFOs off, which clears IIR's FIFO bits.
+   this leaves enhanced mode on if there is an EFR */
+fn has_efr<R: Registers>(regs: &R) -> bool
+{
+    regs.write(REG_LINE_CONTROL, LINE_CONTROL_CONFIG_B);
+    regs.write(REG_EFR, EFR_ENHANCED);
+    let found = regs.read(REG_EFR) == EFR_ENHANCED;
+    regs.write(REG_LINE_CONTROL, 0);
+    found
+}
+
+/* tell apart the chips with an EFR, then take them out of enhanced mode */
+fn identify_efr<R: Registers>(regs: &R) -> Variant
+{
+    let id = [ICR_ID1, ICR_ID2, ICR_ID3].map(|index| read_icr(regs, index));
+    let variant = if id[0] == 0x16 && id[1] == 0xc9 && matches!(id[2], 0x50 | 0x52 | 0x54)
+    {
+        Variant::Uart16950
+    }
+    else if XR16850_IDS.contains(&read_device_id(regs))
+    {
+        Variant::Uart16850
+    }
+    else
+    {
+        Variant::Uart16650
+    };
+
+    regs.write(REG_LINE_CONTROL, LINE_CONTROL_CONFIG_B);
+    regs.write(REG_EFR, 0);
+    regs.write(REG_LINE_CONTROL, 0);
+    variant
+}
+
+/* read one of the 16950's indexed control registers. on other chips this reads LSR */
+fn read_icr<R: Registers>(regs: &R, index: u8) -> u8
+{
+    write_icr(regs, ICR_ACR, ACR_ICR_READ);
+    regs.write(REG_SCRATCH, index);
+    let value = regs.read(REG_ICR);
+    write_icr(regs, ICR_ACR, 0);
+    value
+}
+
+fn write_icr<R: Registers>(regs: &R, index: u8, value: u8)
+{
+    regs.write(REG_SCRATCH, index);
+    regs.write(REG_ICR, value);
+}
+
+/* zero the divisor and read back DLM, which the 16850 replaces with its device ID */
+fn read_device_id<R: Registers>(regs: &R) -> u8
+{
+    regs.write(REG_LINE_CONTROL, LINE_CONTROL_DLAB);
+    let (lsb, msb) = (regs.read(REG_DIVISOR_LSB), regs.read(REG_DIVISOR_MSB));
+    regs.write(REG_DIVISOR_LSB, 0);
+    regs.write(REG_DIVISOR_MSB, 0);
+    let id = regs.read(REG_DIVISOR_MSB);
+    regs.write(REG_DIVISOR_LSB, lsb);
+    regs.write(REG_DIVISOR_MSB, msb);
+    regs.write(REG_LINE_CONTROL, 0);
+    id
+}
+
+/* the 16750's 64-byte FIFO bit only sticks if it's written while DLAB is set */
+fn has_64byte_fifo<R: Registers>(regs: &R) -> bool
+{
+    regs.write(REG_FIFO_CONTROL, FIFO_CONTROL_ENABLE | FIFO_CONTROL_64BYTE);
+    let without_dlab = regs.read(REG_IRQ_ID) & IRQ_ID_64BYTE_FIFO;
+    regs.write(REG_FIFO_CONTROL, 0);
+
+    regs.write(REG_LINE_CONTROL, LINE_CONTROL_DLAB);
+    regs.write(REG_FIFO_CONTROL, FIFO_CONTROL_ENABLE | FIFO_CONTROL_64BYTE);
+    let with_dlab = regs.read(REG_IRQ_ID) & IRQ_ID_64BYTE_FIFO;
+    regs.write(REG_FIFO_CONTROL, 0);
+    regs.write(REG_LINE_CONTROL, 0);
+
+    without_dlab == 0 && with_dlab != 0
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use core::cell::Cell;
+    use model::Model;
+
+    /* just enough of each variant's register file to be probed and initialized */
+    struct Chip
+    {
+        variant: Variant,
+        line_control: Cell<u8>,
+        fifo_control: Cell<u8>,
+        scratch: Cell<u8>,
+        efr: Cell<u8>,
+        acr: Cell<u8>,
+        irq_enable: Cell<u8>,
+        modem_control: Cell<u8>,
+        divisor: Cell<(u8, u8)>
+    }
+
+    impl Chip
+    {
+        fn new(variant: Variant) -> Self
+        {
+            Chip
+            {
+                variant,
+                line_control: Cell::new(0),
+                fifo_control: Cell::new(0),
+                scratch: Cell::new(0),
+                efr: Cell::new(0),
+                acr: Cell::new(0),
+                irq_enable: Cell::new(0),
+                modem_control: Cell::new(0),
+                divisor: Cell::new((12, 0))
+            }
+        }
+
+        fn dlab(&self) -> bool { self.line_control.get() & LINE_CONTROL_DLAB != 0 }
+        fn efr_selected(&self) -> bool { self.variant.has_efr() && self.line_control.get() == LINE_CONTROL_CONFIG_B }
+    }
+
+    impl Registers for Chip
+    {
+        fn read(&self, reg: usize) -> u8
+        {
+            let (lsb, msb) = self.divisor.get();
+            match reg
+            {
+                REG_DIVISOR_LSB if self.dlab() && self.variant == Variant::Uart16850 && lsb == 0 && msb == 0 => 0x03,
+                REG_DIVISOR_MSB if self.dlab() && self.variant == Variant::Uart16850 && lsb == 0 && msb == 0 => 0x12,
+                REG_DIVISOR_LSB if self.dlab() => lsb,
+                REG_DIVISOR_MSB if self.dlab() => msb,
+                REG_IRQ_EN => self.irq_enable.get(),
+                REG_EFR if self.efr_selected() => self.efr.get(),
+                REG_IRQ_ID =>
+                {
+                    let fcr = self.fifo_control.get();
+                    let fifos = match (self.variant, fcr & FIFO_CONTROL_ENABLE != 0)
+                    {
+                        (_, false) | (Variant::Uart8250, _) | (Variant::Uart16450, _) => 0,
+                        (Variant::Uart16550, _) => 0b10 << 6,
+                        _ => IRQ_ID_FIFOS_ENABLED | (fcr & FIFO_CONTROL_64BYTE)
+                    };
+                    IRQ_ID_NONE | fifos
+                },
+                REG_LINE_CONTROL => self.line_control.get(),
+                REG_ICR if self.variant == Variant::Uart16950 && self.acr.get() & ACR_ICR_READ != 0 =>
+                {
+                    match self.scratch.get()
+                    {
+                        ICR_ID1 => 0x16,
+                        ICR_ID2 => 0xc9,
+                        ICR_ID3 => 0x52,
+                        _ => 0
+                    }
+                },
+                REG_LINE_STATUS => LINE_STATUS_THRE | LINE_STATUS_TEMT,
+                REG_MODEM_CONTROL => self.modem_control.get(),
+                REG_MODEM_STATUS =>
+                {
+                    /* only loopback is needed, to pass the presence check */
+                    let mcr = ModemControl::from_bits(self.modem_control.get());
+                    let mut lines = 0;
+                    if mcr.loopback && mcr.rts { lines |= MODEM_STATUS_CTS }
+                    if mcr.loopback && mcr.dtr { lines |= MODEM_STATUS_DSR }
+                    if mcr.loopback && mcr.out1 { lines |= MODEM_STATUS_RI }
+                    if mcr.loopback && mcr.out2 { lines |= MODEM_STATUS_DCD }
+                    lines
+                },
+                REG_SCRATCH if self.variant.has_scratch() => self.scratch.get(),
+                _ => 0xff
+            }
+        }
+
+        fn write(&self, reg: usize, val: u8)
+        {
+            let (lsb, msb) = self.divisor.get();
+            match reg
+            {
+                REG_DIVISOR_LSB if self.dlab() => self.divisor.set((val, msb)),
+                REG_DIVISOR_MSB if self.dlab() => self.divisor.set((lsb, val)),
+                REG_IRQ_EN => self.irq_enable.set(val & 0x0f),
+                REG_EFR if self.efr_selected() => self.efr.set(val),
+                REG_FIFO_CONTROL =>
+                {
+                    /* only the 16750 has the 64-byte bit, and only takes it with DLAB set */
+                    let keep_64byte = match (self.variant, self.dlab())
+                    {
+                        (Variant::Uart16750, true) => val,
+                        (Variant::Uart16750, false) => self.fifo_control.get(),
+                        _ => 0
+                    } & FIFO_CONTROL_64BYTE;
+                    self.fifo_control.set((val & !FIFO_CONTROL_64BYTE) | keep_64byte);
+                },
+                REG_LINE_CONTROL => self.line_control.set(val),
+                REG_MODEM_CONTROL => self.modem_control.set(val & 0x1f),
+                REG_ICR if self.variant == Variant::Uart16950 && self.scratch.get() == ICR_ACR => self.acr.set(val),
+                REG_SCRATCH => self.scratch.set(val),
+                _ => ()
+            }
+        }
+    }
+
+    const ALL: [Variant; 8] =
+    [
+        Variant::Uart8250, Variant::Uart16450, Variant::Uart16550, Variant::Uart16550A,
+        Variant::Uart16650, Variant::Uart16750, Variant::Uart16850, Variant::Uart16950
+    ];
+
+    #[test]
+    fn probe_each_variant()
+    {
+        for variant in ALL.iter()
+        {
+            let chip = Chip::new(*variant);
+            chip.scratch.set(0x42);
+            chip.line_control.set(0b0011);
+
+            assert_eq!(Variant::probe(&chip), *variant);
+
+            /* the probe tidies up after itself */
+            assert_eq!(chip.line_control.get(), 0b0011);
+            assert_eq!(chip.fifo_control.get(), 0);
+            assert_eq!(chip.efr.get(), 0);
+            assert_eq!(chip.divisor.get(), (12, 0));
+            if variant.has_scratch() { assert_eq!(chip.scratch.get(), 0x42) }
+        }
+    }
+
+    #[test]
+    fn probe_with_dlab_left_set()
+    {
+        for variant in ALL.iter()
+        {
+            let chip = Chip::new(*variant);
+            chip.divisor.set((12, 0x34));
+            chip.irq_enable.set(0x05);
+            chip.line_control.set(LINE_CONTROL_DLAB | 0b0011);
+
+            assert_eq!(Variant::probe(&chip), *variant);
+            assert_eq!(chip.divisor.get(), (12, 0x34));
+            assert_eq!((chip.irq_enable.get(), chip.line_control.get()), (0, LINE_CONTROL_DLAB | 0b0011));
+        }
+    }
+
+    #[test]
+    fn model_is_a_16550a()
+    {
+        let model = Model::new();
+        let uart = UART::with_registers(&model, Config::default()).unwrap();
+        assert_eq!(uart.variant(), Variant::Uart16550A);
+        assert!(model.fifo_enabled());
+        assert_eq!(uart.tx_fifo_depth(), 16);
+    }
+
+    #[test]
+    fn driver_adapts_to_variant()
+    {
+        for variant in ALL.iter()
+        {
+            let chip = Chip::new(*variant);
+            let uart = UART::with_registers(&chip, Config::default()).unwrap();
+            assert_eq!(uart.variant(), *variant);
+            assert_eq!(uart.tx_fifo_depth(), variant.fifo_depth());
+            assert_eq!(chip.fifo_control.get() & FIFO_CONTROL_ENABLE != 0, variant.has_fifo());
+            assert_eq!(chip.fifo_control.get() & FIFO_CONTROL_64BYTE != 0, *variant == Variant::Uart16750);
+            assert_eq!(chip.efr.get() & EFR_ENHANCED != 0, variant.has_efr());
+            assert_eq!(chip.divisor.get(), (3, 0));
+        }
+
+        /* a known chip isn't probed */
+        let chip = Chip::new(Variant::Uart16650);
+        let uart = UART::with_registers(&chip, Config::default().with_variant(Variant::Uart16450)).unwrap();
+        assert_eq!(uart.variant(), Variant::Uart16450);
+        assert_eq!(chip.fifo_control.get(), 0);
+    }
+}
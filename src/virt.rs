@@ -0,0 +1,176 @@
+/* a virtual 16550 for guests that expect a real one
+ *
+ * A hypervisor traps a guest's loads and stores to the UART's MMIO window and
+ * passes them to load() and store(), which behave as a 16550A would. What the
+ * guest transmits is collected with take_tx(), bytes for the guest are handed
+ * over with inject(), and irq_asserted() says whether the virtual IRQ line
+ * should be raised after each access or injection.
+ *
+ * The virtual line runs as fast as the hypervisor collects and injects bytes,
+ * whatever baud rate the guest programs. The IRQ follows the interrupt logic
+ * alone, as OUT2 only gates it on PC-style boards, and the modem inputs start
+ * out with CTS, DSR and DCD asserted so the guest sees something connected.
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See README and LICENSE for usage and copying.
+ */
+
+use super::*;
+use device::Device;
+
+/* the guest-facing device. the hypervisor should hold it under its own lock */
+pub struct VirtualUART
+{
+    device: Device
+}
+
+impl Default for VirtualUART
+{
+    fn default() -> Self { VirtualUART::new() }
+}
+
+impl VirtualUART
+{
+    /* create a device in its power-on state */
+    pub fn new() -> Self
+    {
+        let mut device = Device::new();
+        device.set_modem_inputs(MODEM_STATUS_CTS | MODEM_STATUS_DSR | MODEM_STATUS_DCD);
+
+        /* the guest didn't see those lines change */
+        device.load(REG_MODEM_STATUS);
+        VirtualUART { device }
+    }
+
+    /* put the device back in its power-on state, throwing away anything in flight */
+    pub fn reset(&mut self)
+    {
+        *self = VirtualUART::new();
+    }
+
+    /* emulate a byte load from register reg, which is the offset into the MMIO window
+       shifted down by the register spacing. registers beyond the 16550's read as 0xff */
+    pub fn load(&mut self, reg: usize) -> u8
+    {
+        self.device.load(reg)
+    }
+
+    /* emulate a byte store to register reg. stores to registers beyond the 16550's are ignored */
+    pub fn store(&mut self, reg: usize, val: u8)
+    {
+        self.device.store(reg, val)
+    }
+
+    /* collect the next byte transmitted by the guest, if any. while bytes go uncollected,
+       the guest's TX FIFO fills up and it sees the transmitter busy, so none are lost */
+    pub fn take_tx(&mut self) -> Option<u8>
+    {
+        self.device.take_tx()
+    }
+
+    /* pass a byte to the guest. returns false, and drops nothing, if the guest's RX FIFO is
+       full, in which case hold on to the byte and try again once the guest has read some.
+       the line is then idle as far as the guest can tell, so its character timeout
+       interrupt is raised for bytes below the RX trigger level */
+    pub fn inject(&mut self, byte: u8) -> bool
+    {
+        if self.device.rx_len() >= self.device.rx_capacity() { return false }
+        self.device.receive(byte, 0);
+        self.device.character_timeout();
+        true
+    }
+
+    /* pass a break condition to the guest, received as a 0x00 byte with LSR BI set */
+    pub fn inject_break(&mut self) -> bool
+    {
+        if self.device.rx_len() >= self.device.rx_capacity() { return false }
+        self.device.receive(0, LINE_STATUS_BI | LINE_STATUS_FE);
+        self.device.character_timeout();
+        true
+    }
+
+    /* drive the guest's modem status inputs */
+    pub fn set_modem_inputs(&mut self, status: ModemStatus)
+    {
+        self.device.set_modem_inputs(status.bits())
+    }
+
+    /* return true if the virtual IRQ line should be asserted */
+    pub fn irq_asserted(&self) -> bool
+    {
+        self.device.interrupt_pending()
+    }
+
+    /* inspect the guest's settings */
+    pub fn divisor(&self) -> u16 { self.device.divisor() }
+    pub fn line_control(&self) -> u8 { self.device.line_control() }
+    pub fn modem_control(&self) -> ModemControl { self.device.modem_control() }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use core::cell::RefCell;
+
+    /* trap the guest's accesses, as a hypervisor would */
+    struct Trap(RefCell<VirtualUART>);
+
+    impl Registers for Trap
+    {
+        fn read(&self, reg: usize) -> u8 { self.0.borrow_mut().load(reg) }
+        fn write(&self, reg: usize, val: u8) { self.0.borrow_mut().store(reg, val) }
+    }
+
+    #[test]
+    fn guest_driver_runs()
+    {
+        let trap = Trap(RefCell::new(VirtualUART::new()));
+        let guest = UART::with_registers(&trap, Config::default().with_baud_rate(115200)).unwrap();
+        assert_eq!(guest.variant(), Variant::Uart16550A);
+        assert_eq!(trap.0.borrow().divisor(), 1);
+        assert!(guest.modem_status().dcd);
+        assert_eq!(guest.self_test(), Ok(()));
+
+        guest.write_all(b"boot").unwrap();
+        let sent: Vec<u8> = core::iter::from_fn(|| trap.0.borrow_mut().take_tx()).collect();
+        assert_eq!(sent, b"boot");
+
+        /* one byte below the default trigger level still interrupts the guest */
+        assert!(!trap.0.borrow().irq_asserted());
+        assert!(trap.0.borrow_mut().inject(b'y'));
+        assert!(trap.0.borrow().irq_asserted());
+        assert!(guest.handle_interrupt().rx_ready());
+        assert_eq!(guest.read_byte(), Ok(b'y'));
+        assert!(!trap.0.borrow().irq_asserted());
+
+        trap.0.borrow_mut().inject_break();
+        assert_eq!(guest.read_byte(), Err(Fault::Break));
+    }
+
+    #[test]
+    fn nothing_lost()
+    {
+        let mut virt = VirtualUART::new();
+        virt.store(REG_FIFO_CONTROL, FIFO_CONTROL_ENABLE);
+
+        /* the guest's RX FIFO fills up, and the host holds on to the rest */
+        assert_eq!((0..20).filter(|byte| virt.inject(*byte)).count(), FIFO_DEPTH);
+        assert_eq!(virt.load(REG_LINE_STATUS) & LINE_STATUS_OE, 0);
+
+        /* the guest's TX FIFO backs up while the host isn't collecting */
+        let mut sent = 0;
+        while virt.load(REG_LINE_STATUS) & LINE_STATUS_THRE != 0
+        {
+            for _ in 0..FIFO_DEPTH { virt.store(REG_DATA, sent as u8); sent += 1 }
+        }
+        assert!(sent > 256);
+        let collected: Vec<u8> = core::iter::from_fn(|| virt.take_tx()).collect();
+        assert_eq!(collected.len(), sent);
+        assert!(collected.iter().enumerate().all(|(i, byte)| *byte == i as u8));
+
+        virt.reset();
+        assert_eq!(virt.load(REG_LINE_STATUS) & LINE_STATUS_DR, 0);
+    }
+}